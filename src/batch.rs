@@ -0,0 +1,511 @@
+use crate::buildgen::BuildFile;
+use crate::config::{exit_with, BatchArgs, ParsedArgs, Settings};
+use crate::convert::STDIN_FILE;
+use crate::error::{Error, Result, Span};
+use crate::watch::Watcher;
+use crate::{Config, Convert};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The file extension of templates found in input directories.
+pub const TEMPLATE_EXTENSION: &str = "pytv";
+
+/// Converts many templates, collecting the result of each one.
+///
+/// # Example
+/// ```no_run
+/// use pytv::{Batch, Convert};
+/// let mut batch = Batch::default().workers(4);
+/// for input in ["a.pytv", "b.pytv"] {
+///     batch.add(input, Convert::builder().input(input).build());
+/// }
+/// let summary = batch.run();
+/// summary.print();
+/// std::process::exit(summary.exit_code());
+/// ```
+#[derive(Debug)]
+pub struct Batch {
+    jobs: Vec<(String, Result<Convert>)>,
+    skipped: Vec<(String, String)>,
+    config_files: Vec<String>,
+    workers: usize,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Batch {
+            jobs: Vec::new(),
+            skipped: Vec::new(),
+            config_files: Vec::new(),
+            workers: 1,
+        }
+    }
+}
+
+/// The results of a [`Batch`].
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    /// The inputs converted successfully, with the main output file
+    /// (the Verilog if the Python script is run, otherwise the Python script).
+    pub generated: Vec<(String, PathBuf)>,
+    /// The inputs skipped, with the reason.
+    pub skipped: Vec<(String, String)>,
+    /// The inputs failed, with the error
+    /// (the input is empty for an error about the whole batch).
+    pub failed: Vec<(String, Error)>,
+    /// The files the templates depend on, as far as they are known (also for failed templates):
+    /// the templates, included templates, preambles, project configuration files
+    /// and files read by the Python scripts.
+    pub dependencies: Vec<String>,
+}
+
+/// A command of the `pytv` binary.
+#[derive(Debug)]
+pub enum Command {
+    /// Convert templates.
+    Convert(Batch),
+    /// Write a build file (`pytv buildgen`).
+    Buildgen(BuildFile),
+    /// Convert templates whenever their inputs change (`pytv --watch`).
+    Watch(Watcher),
+}
+
+impl Command {
+    /// Parses the command line arguments into a command.
+    ///
+    /// See [`Batch::from_args`] for conversion.
+    /// `pytv buildgen` creates a [`BuildFile`]; both exit on errors.
+    /// With `--watch`, a [`Watcher`] converts the templates repeatedly.
+    pub fn from_args() -> Command {
+        match Config::parse_command_line() {
+            ParsedArgs::Convert(settings, batch_args) if batch_args.watch => {
+                Command::Watch(Watcher::new(settings, batch_args))
+            }
+            ParsedArgs::Convert(settings, batch_args) => Command::Convert(
+                Batch::from_settings(settings, batch_args).unwrap_or_else(|err| exit_with(err)),
+            ),
+            ParsedArgs::Buildgen(settings, buildgen_args) => Command::Buildgen(
+                BuildFile::from_settings(settings, *buildgen_args)
+                    .unwrap_or_else(|err| exit_with(err)),
+            ),
+        }
+    }
+}
+
+impl Batch {
+    /// Creates a `Batch` by parsing command line arguments.
+    ///
+    /// Inputs may be files, directories (searched recursively for `*.pytv`) or glob patterns.
+    /// With `--out-dir`, outputs mirror the tree below each input directory (or glob prefix).
+    /// Each template is configured separately (see [`Config::args_builder`]),
+    /// so that errors in one of them do not stop the others.
+    /// With `-j N`, up to `N` templates are converted at a time.
+    pub fn from_args() -> Batch {
+        let (settings, batch_args) = Config::parse_args();
+        Self::from_settings(settings, batch_args).unwrap_or_else(|err| exit_with(err))
+    }
+
+    /// Creates a `Batch` from the parsed command line arguments.
+    ///
+    /// Errors of a single template are kept in the batch;
+    /// an error is returned for stdin or for the options about the set of inputs.
+    pub(crate) fn from_settings(settings: Settings, batch_args: BatchArgs) -> Result<Batch> {
+        let mut batch = Batch::default().workers(batch_args.jobs);
+        if batch_args.inputs == ["-"] {
+            let settings = settings.for_input("-", None)?;
+            Self::stdin_job(settings, &mut batch)?;
+            return Ok(batch);
+        }
+        let templates = batch.expand_inputs(&batch_args.inputs);
+        if templates.len() > 1 && settings.has_output() {
+            return Err(Error::Config {
+                span: Span::default(),
+                message: "An output file (`-o`) cannot be given for multiple templates; use `--out-dir` instead.".to_string(),
+            });
+        }
+        for (template, base) in templates {
+            let input = template.to_string_lossy().replace("\\", "/");
+            let output = batch_args
+                .out_dir
+                .as_ref()
+                .map(|dir| mirror_output(&template, &base, Path::new(dir)));
+            let input_settings = settings.for_input(&input, output);
+            if let Some(project_file) = input_settings
+                .as_ref()
+                .ok()
+                .and_then(Settings::project_file)
+            {
+                batch.config_files.push(project_file.to_string());
+            }
+            if settings.print_config() {
+                match input_settings {
+                    Ok(input_settings) => input_settings.print(),
+                    Err(err) => eprintln!("Error: {}", err),
+                }
+                continue;
+            }
+            let convert = input_settings
+                .and_then(Settings::into_builder)
+                .and_then(|builder| builder.build());
+            batch.add(&input, convert);
+        }
+        if settings.print_config() {
+            std::process::exit(0);
+        }
+        Ok(batch)
+    }
+
+    /// Adds the job reading the template from stdin, or prints its configuration and exits.
+    fn stdin_job(settings: Settings, batch: &mut Batch) -> Result<()> {
+        if settings.print_config() {
+            settings.print();
+            std::process::exit(0);
+        }
+        let convert = settings
+            .into_builder()?
+            .build()?
+            .with_reader(STDIN_FILE, std::io::stdin().lock())?;
+        batch.add(STDIN_FILE, Ok(convert));
+        Ok(())
+    }
+
+    /// Sets the number of templates converted at a time (0 for the number of CPUs).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = match workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        self
+    }
+
+    /// Adds a template, or the error creating its `Convert`.
+    pub fn add(&mut self, input: &str, convert: Result<Convert>) {
+        self.jobs.push((input.to_string(), convert));
+    }
+
+    /// Records an input that is skipped, with the reason.
+    pub fn skip(&mut self, input: &str, reason: &str) {
+        self.skipped.push((input.to_string(), reason.to_string()));
+    }
+
+    /// Returns the number of templates added.
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Returns `true` if no template is added.
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Expands the inputs into templates, each with the directory its outputs are mirrored from.
+    ///
+    /// Globs matching nothing, directories without templates and duplicates are skipped.
+    /// Other inputs are kept even if they do not exist, so that the error is reported for them.
+    pub(crate) fn expand_inputs(&mut self, inputs: &[String]) -> Vec<(PathBuf, PathBuf)> {
+        let mut seen = HashSet::new();
+        let mut templates = Vec::new();
+        for input in inputs {
+            let path = Path::new(input);
+            let (matches, base) = if path.is_dir() {
+                let pattern = format!(
+                    "{}/**/*.{}",
+                    glob::Pattern::escape(input.trim_end_matches(['/', '\\'])),
+                    TEMPLATE_EXTENSION
+                );
+                (Self::glob_files(&pattern), path.to_path_buf())
+            } else if !path.exists() && input.contains(['*', '?', '[']) {
+                (Self::glob_files(input), glob_base(input))
+            } else {
+                let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                (vec![path.to_path_buf()], base)
+            };
+            if matches.is_empty() {
+                self.skip(input, "no templates found");
+            }
+            for template in matches {
+                let key = template.canonicalize().unwrap_or_else(|_| template.clone());
+                if seen.insert(key) {
+                    templates.push((template, base.clone()));
+                } else {
+                    self.skip(&template.to_string_lossy(), "duplicate input");
+                }
+            }
+        }
+        templates
+    }
+
+    /// Returns the files matching a glob pattern, ignoring unreadable entries.
+    fn glob_files(pattern: &str) -> Vec<PathBuf> {
+        glob::glob(pattern)
+            .map(|paths| paths.flatten().filter(|path| path.is_file()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Checks that no two templates write the same output file.
+    pub fn check_outputs(&self) -> Result<()> {
+        let mut writers = HashMap::new();
+        for (input, convert) in &self.jobs {
+            let Ok(convert) = convert else { continue };
+            let outputs = convert.outputs();
+            let depfile = convert.file_options.depfile.as_ref().map(PathBuf::from);
+            for file in [
+                outputs.verilog_file,
+                outputs.python_script_file,
+                outputs.inst_file,
+                outputs.cache_file,
+            ]
+            .into_iter()
+            .chain(depfile)
+            {
+                let key = std::path::absolute(&file).unwrap_or_else(|_| file.clone());
+                if let Some(other) = writers.insert(key, input) {
+                    return Err(Error::Config {
+                        span: Span::default(),
+                        message: format!(
+                            "Inputs `{}` and `{}` would both write `{}`.",
+                            other,
+                            input,
+                            file.display()
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts all templates (and runs the Python scripts if configured).
+    ///
+    /// Templates are converted by a pool of workers (see [`Batch::workers`]),
+    /// and the results are kept in the order the templates are added.
+    /// Nothing is converted if two templates would write the same output file
+    /// (see [`Batch::check_outputs`]).
+    pub fn run(self) -> BatchSummary {
+        let mut summary = BatchSummary {
+            skipped: self.skipped.clone(),
+            dependencies: self.config_files.clone(),
+            ..Default::default()
+        };
+        if let Err(err) = self.check_outputs() {
+            summary.failed.push((String::new(), err));
+            return summary;
+        }
+        // the Python scripts of templates written to stdout would interleave,
+        // and embedded Python scripts change the working directory of the process
+        let sequential = |c: &Convert| {
+            #[cfg(feature = "embed-python")]
+            if c.python_backend() == crate::PythonBackend::Embedded {
+                return true;
+            }
+            c.file_options.stdout
+        };
+        let workers = if self
+            .jobs
+            .iter()
+            .any(|(_, c)| c.as_ref().is_ok_and(sequential))
+        {
+            1
+        } else {
+            self.workers.clamp(1, self.jobs.len().max(1))
+        };
+        let total = self.jobs.len();
+        let queue = Mutex::new(self.jobs.into_iter().enumerate());
+        let results = Mutex::new(Vec::with_capacity(total));
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let Some((index, (input, convert))) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let mut deps = Vec::new();
+                    let result = convert.and_then(|convert| Self::run_job(&convert, &mut deps));
+                    results.lock().unwrap().push((index, input, result, deps));
+                });
+            }
+        });
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, ..)| *index);
+        for (_, input, result, deps) in results {
+            summary.dependencies.extend(deps);
+            match result {
+                Ok(Some(output)) => summary.generated.push((input, output)),
+                Ok(None) => summary.skipped.push((input, "up to date".to_string())),
+                Err(err) => {
+                    // the file with the error may be an include or a configuration file
+                    let file = &err.span().file;
+                    if !file.is_empty() && !file.starts_with('<') {
+                        summary.dependencies.push(file.clone());
+                    }
+                    summary.failed.push((input, err))
+                }
+            }
+        }
+        summary.dependencies.sort();
+        summary.dependencies.dedup();
+        summary
+    }
+
+    /// Converts a template and returns its main output file, or `None` if it is up to date.
+    ///
+    /// The files the template depends on are added to `deps`.
+    fn run_job(convert: &Convert, deps: &mut Vec<String>) -> Result<Option<PathBuf>> {
+        if !convert.update_files_tracked(deps)? {
+            return Ok(None);
+        }
+        let outputs = convert.outputs();
+        Ok(Some(if convert.file_options.stdout {
+            PathBuf::from("-")
+        } else if convert.config.run_python() {
+            outputs.verilog_file
+        } else {
+            outputs.python_script_file
+        }))
+    }
+}
+
+impl BatchSummary {
+    /// Returns the exit code of the first failure, or 0 if none failed.
+    pub fn exit_code(&self) -> i32 {
+        self.failed.first().map_or(0, |(_, err)| err.exit_code())
+    }
+
+    /// Prints the errors, followed by a summary if there is more than one input.
+    ///
+    /// Everything is printed to stderr, so that it does not mix with `--stdout` output.
+    pub fn print(&self) {
+        for (_, err) in &self.failed {
+            eprintln!("Error: {}", err);
+        }
+        let total = self.generated.len() + self.skipped.len() + self.failed.len();
+        if total <= 1 && self.skipped.is_empty() {
+            return;
+        }
+        eprintln!(
+            "Summary: {} generated, {} skipped, {} failed",
+            self.generated.len(),
+            self.skipped.len(),
+            self.failed.len()
+        );
+        for (input, output) in &self.generated {
+            eprintln!("  generated: {} -> {}", input, output.display());
+        }
+        for (input, reason) in &self.skipped {
+            eprintln!("  skipped: {} ({})", input, reason);
+        }
+        for (input, err) in self.failed.iter().filter(|(input, _)| !input.is_empty()) {
+            eprintln!("  failed: {} (exit code {})", input, err.exit_code());
+        }
+    }
+}
+
+/// Returns the leading components of a glob pattern without wildcards.
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+/// Returns the output Verilog file of `input` in `out_dir`,
+/// at the same relative path as `input` has to `base`.
+pub(crate) fn mirror_output(input: &Path, base: &Path, out_dir: &Path) -> String {
+    let relative = input
+        .strip_prefix(base)
+        .ok()
+        .or_else(|| input.file_name().map(Path::new))
+        .unwrap_or(input);
+    Convert::output_paths(&out_dir.join(relative), None)
+        .verilog_file
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_batch_inputs_and_summary() {
+        let dir = TempDir::new("batch");
+        for file in ["rtl/top.pytv", "rtl/core/alu.pytv", "rtl/notes.txt"] {
+            dir.write(file, "wire w;\n");
+        }
+        let rtl = dir.join("rtl").to_string_lossy().to_string();
+        let mut batch = Batch::default();
+        let templates =
+            batch.expand_inputs(&[rtl.clone(), format!("{rtl}/*.pytv"), format!("{rtl}/*.sv")]);
+        assert_eq!(templates.len(), 2);
+        let outputs = templates
+            .iter()
+            .map(|(template, base)| mirror_output(template, base, Path::new("out")))
+            .collect::<Vec<_>>();
+        assert!(outputs.contains(&"out/top.v".to_string()));
+        assert!(outputs.contains(&"out/core/alu.v".to_string()));
+
+        let out = dir.join("out/top.v").to_string_lossy().to_string();
+        batch.add(&rtl, Convert::builder().input(&rtl).output(&out).build());
+        let missing = dir.join("missing.pytv").to_string_lossy().to_string();
+        batch.add(&missing, Convert::builder().input(&missing).build());
+        batch.add("bad.pytv", Convert::builder().delimiters("!", "!").build());
+        let summary = batch.run();
+        assert_eq!(summary.generated.len(), 0);
+        assert_eq!(summary.failed.len(), 3);
+        assert!(matches!(summary.failed[1].1, Error::Io { .. }));
+        assert!(matches!(summary.failed[2].1, Error::Config { .. }));
+        assert_eq!(summary.exit_code(), summary.failed[0].1.exit_code());
+        assert_eq!(summary.skipped.len(), 2);
+        assert_eq!(summary.skipped[1].1, "no templates found");
+    }
+
+    #[test]
+    fn test_batch_workers_and_output_collision() {
+        let dir = TempDir::new("workers");
+        let mut batch = Batch::default().workers(3);
+        for i in 0..5 {
+            let input = dir.join(format!("t{i}.pytv")).to_string_lossy().to_string();
+            let source = format!("wire w{i};\n");
+            let convert = Convert::builder().source(&input, &source).build();
+            batch.add(&input, convert);
+        }
+        let summary = batch.run();
+        assert_eq!(summary.generated.len(), 5);
+        for (i, (input, output)) in summary.generated.iter().enumerate() {
+            assert!(input.ends_with(&format!("t{i}.pytv")));
+            let script = std::fs::read_to_string(output).unwrap();
+            assert!(script.contains(&format!("print(f'wire w{i};')")));
+        }
+
+        let mut batch = Batch::default().workers(2);
+        let a = dir.join("a.pytv").to_string_lossy().to_string();
+        let b = dir.join("a.tv").to_string_lossy().to_string();
+        batch.add(&a, Convert::builder().source(&a, "wire a;\n").build());
+        batch.add(&b, Convert::builder().source(&b, "wire b;\n").build());
+        let summary = batch.run();
+        assert!(summary.generated.is_empty());
+        assert!(matches!(summary.failed[0].1, Error::Config { .. }));
+        assert!(!dir.join("a.v.py").exists());
+    }
+
+    #[test]
+    fn test_batch_dependencies_include_failed_templates() {
+        let dir = TempDir::new("watch");
+        let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+        std::fs::write(file("ok.pytv"), "//! <INCLUDE inc.pytv>\n").unwrap();
+        std::fs::write(file("inc.pytv"), "wire w;\n").unwrap();
+        std::fs::write(file("bad.pytv"), "//! <INCLUDE broken.pytv>\n").unwrap();
+        std::fs::write(file("broken.pytv"), "//! x = (\n").unwrap();
+        let mut batch = Batch::default();
+        for name in ["ok.pytv", "bad.pytv"] {
+            batch.add(name, Convert::builder().input(file(name)).build());
+        }
+        let summary = batch.run();
+        assert_eq!(summary.generated.len(), 1);
+        assert_eq!(summary.failed.len(), 1);
+        for name in ["ok.pytv", "inc.pytv", "bad.pytv", "broken.pytv"] {
+            assert!(summary.dependencies.contains(&file(name)), "{name}");
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use crate::config::{OnFailure, PythonBackend, RunMode};
+use crate::error::Result;
+use crate::limits::ResourceLimits;
+use crate::{Config, Convert, FileOptions};
+use regex::Regex;
+
+/// Builds a [`Config`] with named settings.
+///
+/// # Example
+/// ```
+/// use pytv::{Config, RunMode};
+/// let config = Config::builder()
+///     .magic("#")
+///     .delimiters("${", "}")
+///     .tab_size(2)
+///     .run_mode(RunMode::RunAndDelete)
+///     .build()
+///     .unwrap();
+/// assert!(config.run_python() && config.delete_python());
+/// assert!(config.template_re.is_match("wire ${name};"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    magic_comment_str: String,
+    open_delimiter: String,
+    close_delimiter: String,
+    template_re: Option<Regex>,
+    run_mode: RunMode,
+    tab_size: u32,
+    include_paths: Vec<String>,
+    python: Option<String>,
+    python_path: Vec<String>,
+    python_env: Vec<(String, String)>,
+    python_cwd: Option<String>,
+    limits: ResourceLimits,
+    python_stdin: bool,
+    python_backend: PythonBackend,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder {
+            magic_comment_str: Config::default_magic_comment_str(),
+            open_delimiter: "`".to_string(),
+            close_delimiter: "`".to_string(),
+            template_re: None,
+            run_mode: RunMode::default(),
+            tab_size: 4,
+            include_paths: Vec::new(),
+            python: None,
+            python_path: Vec::new(),
+            python_env: Vec::new(),
+            python_cwd: None,
+            limits: ResourceLimits::default(),
+            python_stdin: false,
+            python_backend: PythonBackend::Process,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Sets the magic comment string (after `//` and `/*`).
+    pub fn magic(mut self, magic_comment_str: impl Into<String>) -> Self {
+        self.magic_comment_str = magic_comment_str.into();
+        self
+    }
+
+    /// Sets the delimiters of substituted expressions.
+    pub fn delimiters(mut self, open: impl Into<String>, close: impl Into<String>) -> Self {
+        self.open_delimiter = open.into();
+        self.close_delimiter = close.into();
+        self
+    }
+
+    /// Sets a custom regular expression matching substituted expressions.
+    ///
+    /// By default, it is derived from the delimiters (see [`Config::template_re_for`]).
+    pub fn template_re(mut self, template_re: Regex) -> Self {
+        self.template_re = Some(template_re);
+        self
+    }
+
+    /// Sets whether the Python script is run, and whether it is deleted afterwards.
+    pub fn run_mode(mut self, run_mode: RunMode) -> Self {
+        self.run_mode = run_mode;
+        self
+    }
+
+    /// Sets the tab size.
+    pub fn tab_size(mut self, tab_size: u32) -> Self {
+        self.tab_size = tab_size;
+        self
+    }
+
+    /// Adds a directory searched for included templates.
+    pub fn include_path(mut self, dir: impl Into<String>) -> Self {
+        self.include_paths.push(dir.into());
+        self
+    }
+
+    /// Sets the Python interpreter.
+    pub fn python(mut self, python: impl Into<String>) -> Self {
+        self.python = Some(python.into());
+        self
+    }
+
+    /// Adds a directory prepended to the `PYTHONPATH` of the Python script.
+    pub fn python_path(mut self, dir: impl Into<String>) -> Self {
+        self.python_path.push(dir.into());
+        self
+    }
+
+    /// Adds an environment variable of the Python script.
+    pub fn python_env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.python_env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the working directory of the Python script (the template's directory by default).
+    pub fn python_cwd(mut self, dir: impl Into<String>) -> Self {
+        self.python_cwd = Some(dir.into());
+        self
+    }
+
+    /// Sets the limits of the Python script.
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets whether the Python script is piped to the interpreter over stdin.
+    pub fn python_stdin(mut self, python_stdin: bool) -> Self {
+        self.python_stdin = python_stdin;
+        self
+    }
+
+    /// Sets how the Python script is run.
+    pub fn python_backend(mut self, python_backend: PythonBackend) -> Self {
+        self.python_backend = python_backend;
+        self
+    }
+
+    /// Builds the `Config` without checking the delimiters.
+    pub(crate) fn build_unchecked(self) -> Config {
+        let template_re = self.template_re.unwrap_or_else(|| {
+            Config::template_re_for(&self.open_delimiter, &self.close_delimiter)
+        });
+        Config {
+            magic_comment_str: self.magic_comment_str,
+            template_re,
+            open_delimiter: self.open_delimiter,
+            close_delimiter: self.close_delimiter,
+            run_mode: self.run_mode,
+            tab_size: self.tab_size,
+            include_paths: self.include_paths,
+            python: self.python,
+            python_path: self.python_path,
+            python_env: self.python_env,
+            python_cwd: self.python_cwd,
+            limits: self.limits,
+            python_stdin: self.python_stdin,
+            python_backend: self.python_backend,
+        }
+    }
+
+    /// Builds the `Config`.
+    ///
+    /// Returns an error if the delimiters are invalid (see [`Config::check_delimiters`]).
+    pub fn build(self) -> Result<Config> {
+        let config = self.build_unchecked();
+        config.check_delimiters()?;
+        Ok(config)
+    }
+}
+
+/// Builds a [`Convert`] with named settings.
+///
+/// # Example
+/// ```
+/// use pytv::{Convert, RunMode};
+/// let convert = Convert::builder()
+///     .source("top.pytv", "wire [`N-1`:0] w;\n")
+///     .output("build/top.v")
+///     .var("N", "8")
+///     .run_mode(RunMode::Convert)
+///     .build()
+///     .unwrap();
+/// let script = convert.render_python_script().unwrap();
+/// assert!(script.contains("N = 8"));
+/// assert!(script.contains("print(f'wire [{N-1}:0] w;')"));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ConvertBuilder {
+    config: ConfigBuilder,
+    file_options: FileOptions,
+    vars: Vec<(String, String)>,
+    preambles: Vec<String>,
+    source: Option<String>,
+}
+
+impl ConvertBuilder {
+    /// Sets all configuration options at once.
+    pub fn config(mut self, config: ConfigBuilder) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the magic comment string (after `//` and `/*`).
+    pub fn magic(mut self, magic_comment_str: impl Into<String>) -> Self {
+        self.config = self.config.magic(magic_comment_str);
+        self
+    }
+
+    /// Sets the delimiters of substituted expressions.
+    pub fn delimiters(mut self, open: impl Into<String>, close: impl Into<String>) -> Self {
+        self.config = self.config.delimiters(open, close);
+        self
+    }
+
+    /// Sets whether the Python script is run, and whether it is deleted afterwards.
+    pub fn run_mode(mut self, run_mode: RunMode) -> Self {
+        self.config = self.config.run_mode(run_mode);
+        self
+    }
+
+    /// Sets the tab size.
+    pub fn tab_size(mut self, tab_size: u32) -> Self {
+        self.config = self.config.tab_size(tab_size);
+        self
+    }
+
+    /// Adds a directory searched for included templates.
+    pub fn include_path(mut self, dir: impl Into<String>) -> Self {
+        self.config = self.config.include_path(dir);
+        self
+    }
+
+    /// Sets the Python interpreter.
+    pub fn python(mut self, python: impl Into<String>) -> Self {
+        self.config = self.config.python(python);
+        self
+    }
+
+    /// Adds a directory prepended to the `PYTHONPATH` of the Python script.
+    pub fn python_path(mut self, dir: impl Into<String>) -> Self {
+        self.config = self.config.python_path(dir);
+        self
+    }
+
+    /// Adds an environment variable of the Python script.
+    pub fn python_env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config = self.config.python_env(name, value);
+        self
+    }
+
+    /// Sets the working directory of the Python script (the template's directory by default).
+    pub fn python_cwd(mut self, dir: impl Into<String>) -> Self {
+        self.config = self.config.python_cwd(dir);
+        self
+    }
+
+    /// Sets the limits of the Python script.
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.config = self.config.limits(limits);
+        self
+    }
+
+    /// Sets whether the Python script is piped to the interpreter over stdin.
+    pub fn python_stdin(mut self, python_stdin: bool) -> Self {
+        self.config = self.config.python_stdin(python_stdin);
+        self
+    }
+
+    /// Sets how the Python script is run.
+    pub fn python_backend(mut self, python_backend: PythonBackend) -> Self {
+        self.config = self.config.python_backend(python_backend);
+        self
+    }
+
+    /// Sets the input file.
+    pub fn input(mut self, input: impl Into<String>) -> Self {
+        self.file_options.input = input.into();
+        self
+    }
+
+    /// Sets the template source, which is used instead of reading the input file.
+    ///
+    /// See [`Convert::with_source`] for the meaning of `file_name`.
+    pub fn source(mut self, file_name: impl Into<String>, source: impl Into<String>) -> Self {
+        self.file_options.input = file_name.into();
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Sets the output Verilog file, from which the other output files are derived.
+    pub fn output(mut self, output: impl Into<String>) -> Self {
+        self.file_options.output = Some(output.into());
+        self
+    }
+
+    /// Sets whether to write the Python script (or the Verilog, if Python is run) to stdout.
+    pub fn stdout(mut self, stdout: bool) -> Self {
+        self.file_options.stdout = stdout;
+        self
+    }
+
+    /// Sets whether to skip the conversion if the inputs are unchanged since the last one.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.file_options.cache = cache;
+        self
+    }
+
+    /// Sets whether to convert even if the outputs are up to date.
+    pub fn force(mut self, force: bool) -> Self {
+        self.file_options.force = force;
+        self
+    }
+
+    /// Sets the depfile listing the output files and the files they depend on.
+    pub fn depfile(mut self, depfile: impl Into<String>) -> Self {
+        self.file_options.depfile = Some(depfile.into());
+        self
+    }
+
+    /// Sets whether the outputs of a previous conversion are kept when the conversion fails.
+    pub fn on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.file_options.on_failure = on_failure;
+        self
+    }
+
+    /// Adds a user-defined variable, whose value is a Python expression.
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a preamble Python file, run before the template.
+    pub fn preamble(mut self, preamble_py: impl Into<String>) -> Self {
+        self.preambles.push(preamble_py.into());
+        self
+    }
+
+    /// Builds the `Convert`.
+    ///
+    /// Returns an error if the configuration is invalid (see [`ConfigBuilder::build`]).
+    pub fn build(self) -> Result<Convert> {
+        let mut convert = Convert::new(self.config.build()?, self.file_options, None, None);
+        convert.vars = self.vars;
+        convert.preambles = self.preambles;
+        convert.source = self.source;
+        Ok(convert)
+    }
+}
@@ -0,0 +1,350 @@
+use crate::batch::mirror_output;
+use crate::config::{BuildgenArgs, Settings};
+use crate::depfile::relative_to_cwd;
+use crate::error::{Error, Result};
+use crate::{Batch, Convert};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+/// Name of the phony target building all Verilog outputs.
+pub const VERILOG_TARGET: &str = "pytv-verilog";
+/// Name of the phony target building all `.inst` outputs.
+pub const INST_TARGET: &str = "pytv-inst";
+
+/// The format of a build file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BuildFormat {
+    /// A `build.ninja` file.
+    Ninja,
+    /// A Makefile fragment.
+    Make,
+}
+
+/// How a template is converted in a build file.
+#[derive(Debug)]
+struct BuildRule {
+    /// The template.
+    input: String,
+    /// The output files (see [`Convert::output_files`]), and the cache manifest if it is kept.
+    outputs: Vec<PathBuf>,
+    /// The output Verilog file.
+    verilog: PathBuf,
+    /// The output `.inst` file.
+    inst: PathBuf,
+    /// The depfile written by PyTV.
+    depfile: String,
+    /// The preambles and the project configuration file, which are known before conversion.
+    implicit_deps: Vec<String>,
+    /// The command line options of PyTV, besides the input and output files.
+    args: Vec<String>,
+}
+
+/// A Ninja build file or a Makefile fragment converting the templates of a project.
+///
+/// Each template has a rule running PyTV with its options (including those from `pytv.toml`),
+/// writing a depfile for the files it depends on.
+/// The phony targets `pytv-verilog` and `pytv-inst` build all Verilog and `.inst` outputs.
+#[derive(Debug)]
+pub struct BuildFile {
+    format: BuildFormat,
+    pytv: String,
+    output: Option<String>,
+    rules: Vec<BuildRule>,
+}
+
+impl BuildFile {
+    /// Creates the build file of the templates found in the inputs of `pytv buildgen`.
+    ///
+    /// Inputs are expanded like in batch mode (see [`Batch::from_args`]),
+    /// and the output paths are those [`Convert`] writes.
+    pub(crate) fn from_settings(settings: Settings, args: BuildgenArgs) -> Result<BuildFile> {
+        let mut build_file = BuildFile::new(args.format, &args.pytv, args.output);
+        let mut batch = Batch::default();
+        for (template, base) in batch.expand_inputs(&args.inputs) {
+            let input = template.to_string_lossy().replace("\\", "/");
+            let output = args
+                .out_dir
+                .as_ref()
+                .map(|dir| mirror_output(&template, &base, Path::new(dir)));
+            let mut settings = settings.for_input(&input, output)?;
+            settings.run_python();
+            let rule_args = settings.to_args();
+            let project_file = settings.project_file().map(relative_to_cwd);
+            let builder = settings.into_builder()?;
+            let outputs = builder.clone().build()?.outputs();
+            let depfile = format!("{}.d", outputs.verilog_file.to_string_lossy());
+            let convert = builder.depfile(depfile).build()?;
+            build_file.add_rule(&convert, rule_args, project_file);
+            batch.add(&input, Ok(convert));
+        }
+        batch.check_outputs()?;
+        Ok(build_file)
+    }
+
+    /// Creates an empty build file, written to `output` (or stdout).
+    pub(crate) fn new(format: BuildFormat, pytv: &str, output: Option<String>) -> BuildFile {
+        BuildFile {
+            format,
+            pytv: pytv.to_string(),
+            output,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Adds the rule converting a template with `convert`, which has a depfile,
+    /// running PyTV with the options `args`.
+    pub(crate) fn add_rule(
+        &mut self,
+        convert: &Convert,
+        args: Vec<String>,
+        project_file: Option<String>,
+    ) {
+        let outputs = convert.outputs();
+        let cache_file = Some(outputs.cache_file).filter(|_| convert.file_options.cache);
+        self.rules.push(BuildRule {
+            input: convert.file_options.input.clone(),
+            outputs: convert
+                .output_files()
+                .into_iter()
+                .chain(cache_file)
+                .collect(),
+            verilog: outputs.verilog_file,
+            inst: outputs.inst_file,
+            depfile: convert.file_options.depfile.clone().unwrap_or_default(),
+            implicit_deps: convert
+                .preambles
+                .iter()
+                .cloned()
+                .chain(project_file)
+                .collect(),
+            args,
+        });
+    }
+
+    /// Returns the contents of the build file.
+    pub fn render(&self) -> String {
+        match self.format {
+            BuildFormat::Ninja => self.render_ninja(),
+            BuildFormat::Make => self.render_make(),
+        }
+    }
+
+    fn render_ninja(&self) -> String {
+        let mut out = String::from("# Generated by `pytv buildgen`.\n");
+        // `deps = gcc` reads depfiles with several outputs since Ninja 1.10
+        out.push_str("ninja_required_version = 1.10\n\n");
+        let _ = writeln!(out, "pytv = {}\n", escape_ninja(&self.pytv, false));
+        out.push_str(concat!(
+            "rule pytv\n",
+            "  command = $pytv $input -o $verilog --depfile $depfile $flags\n",
+            "  description = PYTV $in\n",
+            "  depfile = $depfile\n",
+            "  deps = gcc\n",
+            "  restat = 1\n\n",
+        ));
+        for rule in &self.rules {
+            let outputs = rule
+                .outputs
+                .iter()
+                .map(|p| escape_ninja(&p.to_string_lossy(), true));
+            let _ = write!(
+                out,
+                "build {}: pytv {}",
+                outputs.collect::<Vec<_>>().join(" "),
+                escape_ninja(&rule.input, true)
+            );
+            if !rule.implicit_deps.is_empty() {
+                let deps = rule.implicit_deps.iter().map(|p| escape_ninja(p, true));
+                let _ = write!(out, " | {}", deps.collect::<Vec<_>>().join(" "));
+            }
+            out.push('\n');
+            // `$in` is not quoted for the shell, so the command uses its own copy of the input
+            let _ = writeln!(
+                out,
+                "  input = {}",
+                escape_ninja(&shell_quote(&rule.input), false)
+            );
+            let _ = writeln!(
+                out,
+                "  verilog = {}",
+                escape_ninja(&shell_quote(&rule.verilog.to_string_lossy()), false)
+            );
+            let _ = writeln!(
+                out,
+                "  depfile = {}",
+                escape_ninja(&shell_quote(&rule.depfile), false)
+            );
+            let flags = rule
+                .args
+                .iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "  flags = {}\n", escape_ninja(&flags.join(" "), false));
+        }
+        for (target, files) in self.phony_targets() {
+            let files = files
+                .iter()
+                .map(|p| escape_ninja(&p.to_string_lossy(), true));
+            let _ = writeln!(
+                out,
+                "build {target}: phony {}",
+                files.collect::<Vec<_>>().join(" ")
+            );
+        }
+        let _ = writeln!(out, "default {VERILOG_TARGET} {INST_TARGET}");
+        out
+    }
+
+    fn render_make(&self) -> String {
+        let mut out = String::from("# Generated by `pytv buildgen`.\n");
+        let _ = writeln!(out, "PYTV ?= {}\n", self.pytv.replace('$', "$$"));
+        for rule in &self.rules {
+            let verilog = rule.verilog.to_string_lossy();
+            let prerequisites = std::iter::once(&rule.input).chain(&rule.implicit_deps);
+            let prerequisites = prerequisites.map(|p| escape_make(p));
+            let _ = writeln!(
+                out,
+                "{}: {}",
+                escape_make(&verilog),
+                prerequisites.collect::<Vec<_>>().join(" ")
+            );
+            let command = [
+                rule.input.as_str(),
+                "-o",
+                &verilog,
+                "--depfile",
+                &rule.depfile,
+            ]
+            .into_iter()
+            .chain(rule.args.iter().map(String::as_str))
+            .map(shell_quote)
+            .collect::<Vec<_>>();
+            let _ = writeln!(out, "\t$(PYTV) {}", command.join(" ").replace('$', "$$"));
+            // unchanged outputs are not rewritten, which would make the target always out of date
+            let _ = writeln!(out, "\t@touch $@");
+            let others = rule.outputs.iter().filter(|p| **p != rule.verilog);
+            let others = others
+                .map(|p| escape_make(&p.to_string_lossy()))
+                .collect::<Vec<_>>();
+            if !others.is_empty() {
+                let _ = writeln!(out, "{}: {}", others.join(" "), escape_make(&verilog));
+            }
+            let _ = writeln!(out, "-include {}\n", escape_make(&rule.depfile));
+        }
+        let _ = writeln!(out, ".PHONY: {VERILOG_TARGET} {INST_TARGET}");
+        for (target, files) in self.phony_targets() {
+            let files = files.iter().map(|p| escape_make(&p.to_string_lossy()));
+            let _ = writeln!(out, "{target}: {}", files.collect::<Vec<_>>().join(" "));
+        }
+        out
+    }
+
+    /// Returns the phony targets with the files they build.
+    fn phony_targets(&self) -> [(&str, Vec<&PathBuf>); 2] {
+        [
+            (
+                VERILOG_TARGET,
+                self.rules.iter().map(|r| &r.verilog).collect(),
+            ),
+            (INST_TARGET, self.rules.iter().map(|r| &r.inst).collect()),
+        ]
+    }
+
+    /// Writes the build file to its output file, or to stdout.
+    pub fn write(&self) -> Result<()> {
+        let contents = self.render();
+        match &self.output {
+            Some(output) => std::fs::write(output, contents).map_err(|err| Error::io(output, err)),
+            None => {
+                print!("{contents}");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Quotes an argument for a POSIX shell, if needed.
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./=+,:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Escapes a string for a Ninja file (also spaces and colons in paths).
+fn escape_ninja(s: &str, path: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '$' || (path && (c == ' ' || c == ':')) {
+            escaped.push('$');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes a path for a Makefile.
+fn escape_make(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buildgen_rules() {
+        use crate::RunMode;
+        let convert = Convert::builder()
+            .input("rtl/top core.pytv")
+            .output("build/top.v")
+            .run_mode(RunMode::RunAndDelete)
+            .preamble("pre.py")
+            .depfile("build/top.v.d")
+            .build()
+            .unwrap();
+        let args = vec!["-v".to_string(), "N='a b'".to_string(), "-R".to_string()];
+        let mut ninja = BuildFile::new(BuildFormat::Ninja, "pytv", None);
+        ninja.add_rule(&convert, args.clone(), Some("pytv.toml".to_string()));
+        let ninja = ninja.render();
+        assert!(ninja.contains(
+            "build build/top.v build/top.v.inst: pytv rtl/top$ core.pytv | pre.py pytv.toml\n"
+        ));
+        assert!(ninja.contains("ninja_required_version = 1.10\n"));
+        assert!(ninja.contains("  command = $pytv $input -o $verilog"));
+        assert!(ninja.contains("  input = 'rtl/top core.pytv'\n"));
+        assert!(ninja.contains(r#"  flags = -v 'N='\''a b'\''' -R"#));
+        assert!(ninja.contains("build pytv-verilog: phony build/top.v\n"));
+        let mut make = BuildFile::new(BuildFormat::Make, "pytv", None);
+        make.add_rule(&convert, args, None);
+        let make = make.render();
+        assert!(make.contains("build/top.v: rtl/top\\ core.pytv pre.py\n"));
+        assert!(make
+            .contains("\t$(PYTV) 'rtl/top core.pytv' -o build/top.v --depfile build/top.v.d -v"));
+        assert!(make.contains(" -R\n\t@touch $@\n"));
+        assert!(make.contains("build/top.v.inst: build/top.v\n-include build/top.v.d\n"));
+        assert!(make.contains("pytv-inst: build/top.v.inst\n"));
+        let cached = Convert::builder()
+            .input("rtl/top.pytv")
+            .output("build/top.v")
+            .run_mode(RunMode::RunAndDelete)
+            .cache(true)
+            .depfile("build/top.v.d")
+            .build()
+            .unwrap();
+        let mut ninja = BuildFile::new(BuildFormat::Ninja, "pytv", None);
+        ninja.add_rule(&cached, vec!["--cache".to_string()], None);
+        assert!(ninja
+            .render()
+            .contains("build build/top.v build/top.v.inst build/top.v.cache: pytv rtl/top.pytv\n"));
+        let mut make = BuildFile::new(BuildFormat::Make, "pytv", None);
+        make.add_rule(&cached, vec!["--cache".to_string()], None);
+        assert!(make
+            .render()
+            .contains("build/top.v.inst build/top.v.cache: build/top.v\n"));
+    }
+}
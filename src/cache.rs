@@ -0,0 +1,209 @@
+use super::Convert;
+use crate::error::{Error, Result};
+use crate::source_map::SourceMap;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// The first line of cache manifests.
+const MANIFEST_HEADER: &str = "# PyTV cache manifest";
+
+impl Convert {
+    /// Returns the hash of everything the outputs depend on.
+    ///
+    /// It covers the PyTV version, the run mode, the Python interpreter and its environment, the variables,
+    /// the generated script and the contents of all input files in `source_map`
+    /// (template, included templates and preambles).
+    /// The files read by the Python script and the files of the modules it imports
+    /// (see [`Convert::dependencies`]) are checked separately, with the hashes in the cache manifest.
+    pub fn cache_key(&self, script: &[u8], source_map: &SourceMap) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("pytv {}\n", env!("CARGO_PKG_VERSION")));
+        hasher.update(format!("run {}\n", self.config.run_mode().as_str()));
+        hasher.update(format!("python {:?}\n", self.config.python));
+        hasher.update(format!("python_path {:?}\n", self.config.python_path));
+        hasher.update(format!("python_env {:?}\n", self.config.python_env));
+        hasher.update(format!("python_cwd {:?}\n", self.python_cwd()));
+        for (name, value) in &self.vars {
+            hasher.update(format!("var {name:?} {value:?}\n"));
+        }
+        for input in source_map.inputs() {
+            let contents = match &self.source {
+                Some(source) if input == &self.file_options.input => source.as_bytes().to_vec(),
+                _ => std::fs::read(input).map_err(|err| Error::io(input, err))?,
+            };
+            hasher.update(format!("input {input:?} {}\n", contents.len()));
+            hasher.update(&contents);
+        }
+        hasher.update(format!("script {}\n", script.len()));
+        hasher.update(script);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Returns the files read by the Python script, recorded in the cache manifest,
+    /// if it has the key `key` and all outputs and files read are unchanged.
+    pub(crate) fn up_to_date_deps(&self, key: &str) -> Option<Vec<String>> {
+        let manifest = std::fs::read_to_string(self.outputs().cache_file).ok()?;
+        let mut lines = manifest.lines();
+        if lines.next() != Some(MANIFEST_HEADER) || lines.next() != Some(&format!("key {key}")) {
+            return None;
+        }
+        let mut outputs = 0;
+        let mut deps = Vec::new();
+        for line in lines {
+            let (kind, line) = line.split_once(' ')?;
+            let (hash, file) = line.split_once(' ')?;
+            if std::fs::read(file).map_or(true, |contents| hash_bytes(&contents) != hash) {
+                return None;
+            }
+            match kind {
+                "output" => outputs += 1,
+                "dep" => deps.push(file.to_string()),
+                _ => return None,
+            }
+        }
+        (outputs > 0).then_some(deps)
+    }
+
+    /// Writes the cache manifest with the key `key` and the hashes of the outputs
+    /// and of the files read by the Python script (`runtime_deps`).
+    pub(crate) fn write_cache(&self, key: &str, runtime_deps: &[String]) -> Result<()> {
+        let mut manifest = format!("{MANIFEST_HEADER}\nkey {key}\n");
+        let outputs = self.output_files();
+        let outputs = outputs
+            .iter()
+            .map(|file| ("output", file.to_string_lossy()));
+        let deps = runtime_deps.iter().map(|file| ("dep", file.into()));
+        for (kind, file) in outputs.chain(deps) {
+            if let Ok(contents) = std::fs::read(file.as_ref()) {
+                manifest.push_str(&format!("{kind} {} {file}\n", hash_bytes(&contents)));
+            }
+        }
+        let cache_file = self.outputs().cache_file.to_string_lossy().to_string();
+        write_if_changed(&cache_file, manifest.as_bytes())
+    }
+}
+
+/// Returns the SHA-256 hash of `contents` in hexadecimal.
+fn hash_bytes(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Writes `contents` to `file` unless it already has them, creating its directory if needed.
+///
+/// Skipping byte-identical files keeps their modification times,
+/// so that downstream tools do not rebuild.
+/// The contents are written to a temporary file in the same directory, which is renamed into place,
+/// so that `file` is never left truncated or half-written.
+pub(crate) fn write_if_changed(file: &str, contents: &[u8]) -> Result<()> {
+    if std::fs::read(file).is_ok_and(|old| old == contents) {
+        return Ok(());
+    }
+    let temp = write_temp_file(file, contents)?;
+    replace_if_changed(&temp, file)
+}
+
+/// Writes `contents` to the temporary file of `file` (see [`temp_file`]),
+/// creating its directory if needed, and returns its name.
+pub(crate) fn write_temp_file(file: &str, contents: &[u8]) -> Result<String> {
+    if let Some(dir) = Path::new(file).parent() {
+        std::fs::create_dir_all(dir).map_err(|err| Error::io(file, err))?;
+    }
+    let temp = temp_file(file);
+    std::fs::write(&temp, contents).map_err(|err| Error::io(&temp, err))?;
+    Ok(temp)
+}
+
+/// Returns the temporary file `file` is written to before it is renamed into place.
+pub(crate) fn temp_file(file: &str) -> String {
+    format!("{}.{}.tmp", file, std::process::id())
+}
+
+/// Renames the temporary file `temp` to `file`, or removes it if `file` has the same contents.
+pub(crate) fn replace_if_changed(temp: &str, file: &str) -> Result<()> {
+    let contents = std::fs::read(temp).map_err(|err| Error::io(temp, err))?;
+    if std::fs::read(file).is_ok_and(|old| old == contents) {
+        let _ = std::fs::remove_file(temp);
+        return Ok(());
+    }
+    std::fs::rename(temp, file).map_err(|err| {
+        let _ = std::fs::remove_file(temp);
+        Error::io(file, err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_cache_skips_unchanged_inputs() {
+        let dir = TempDir::new("cache");
+        let input = dir.join("top.pytv").to_string_lossy().to_string();
+        let include = dir.join("inc.pytv");
+        std::fs::write(&input, "//! <INCLUDE inc.pytv>\nwire w`N`;\n").unwrap();
+        std::fs::write(&include, "//! M = 1\n").unwrap();
+        let build = |n: &str| {
+            Convert::builder()
+                .input(&input)
+                .var("N", n)
+                .cache(true)
+                .build()
+                .unwrap()
+        };
+        let convert = build("1");
+        assert!(convert.update_files().unwrap());
+        let outputs = convert.outputs();
+        assert!(outputs.cache_file.exists());
+        assert!(!convert.update_files().unwrap());
+        assert!(build("2").update_files().unwrap());
+        assert!(!build("2").update_files().unwrap());
+        std::fs::write(&include, "//! M = 2\n").unwrap();
+        assert!(build("2").update_files().unwrap());
+        std::fs::write(&outputs.python_script_file, "edited").unwrap();
+        assert!(build("2").update_files().unwrap());
+
+        // unchanged outputs keep their modification time even when forced
+        let modified = || {
+            std::fs::metadata(&outputs.python_script_file)
+                .and_then(|m| m.modified())
+                .unwrap()
+        };
+        let before = modified();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let forced = Convert::builder()
+            .input(&input)
+            .var("N", "2")
+            .cache(true)
+            .force(true)
+            .build()
+            .unwrap();
+        assert!(forced.update_files().unwrap());
+        assert_eq!(modified(), before);
+    }
+
+    #[test]
+    fn test_cache_tracks_imported_modules() {
+        use crate::RunMode;
+        let dir = TempDir::new("cache_import");
+        let input = dir.join("top.pytv").to_string_lossy().to_string();
+        std::fs::write(&input, "//! import json, helper\nwire [`helper.W`:0] a;\n").unwrap();
+        dir.write("helper.py", "W = 4\n");
+        let convert = Convert::builder()
+            .input(&input)
+            .run_mode(RunMode::Run)
+            .cache(true)
+            .build()
+            .unwrap();
+        assert!(convert.update_files().unwrap());
+        assert!(!convert.update_files().unwrap());
+        let outputs = convert.outputs();
+        let manifest = std::fs::read_to_string(&outputs.cache_file).unwrap();
+        assert!(manifest.contains("helper.py"));
+        assert!(!manifest.contains("json"));
+        dir.write("helper.py", "W = 9\n");
+        assert!(convert.update_files().unwrap());
+        let verilog = std::fs::read_to_string(&outputs.verilog_file).unwrap();
+        assert_eq!(verilog, "wire [9:0] a;\n");
+    }
+}
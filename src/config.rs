@@ -1,4 +1,11 @@
-use clap::Parser;
+use crate::builder::{ConfigBuilder, ConvertBuilder};
+use crate::buildgen::BuildFormat;
+use crate::error::{Error as PytvError, Result as PytvResult, Span};
+use crate::limits::ResourceLimits;
+#[cfg(feature = "project")]
+use crate::project::ProjectConfig;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use regex::Regex;
 use std::error::Error;
 
@@ -8,22 +15,161 @@ pub struct Config {
     /// The magic comment string used to identify template sections in the input file.
     pub magic_comment_str: String,
     /// The regular expression used to match template sections in the input file.
+    ///
+    /// If it is the regex of the delimiters (see [`Config::template_re_for`]),
+    /// a tokenizer is used instead, which supports escaped delimiters and,
+    /// for backticks, keeps Verilog compiler directives and macro usages as they are.
     pub template_re: Regex,
-    /// Whether to run the Python script or not.
-    pub run_python: bool,
-    /// Whether to delete the Python script after running or not.
-    pub delete_python: bool,
+    /// The opening delimiter of substituted expressions.
+    pub open_delimiter: String,
+    /// The closing delimiter of substituted expressions.
+    pub close_delimiter: String,
+    /// Whether the Python script is run, and whether it is deleted afterwards
+    /// (see [`Config::run_mode`]).
+    pub(crate) run_mode: RunMode,
     /// The tab size used for parsing in the input file.
     pub tab_size: u32,
+    /// The directories searched for included templates after the including file's directory.
+    pub include_paths: Vec<String>,
+    /// The Python interpreter (`python3`, or `python` for Windows, if not given).
+    pub python: Option<String>,
+    /// Directories prepended to the `PYTHONPATH` of the Python script.
+    pub python_path: Vec<String>,
+    /// Extra environment variables of the Python script.
+    pub python_env: Vec<(String, String)>,
+    /// The working directory of the Python script (the template's directory if not given).
+    pub python_cwd: Option<String>,
+    /// The limits of the Python script.
+    pub limits: ResourceLimits,
+    /// Whether the Python script is piped to the interpreter over stdin when it is run,
+    /// instead of being run from the output Python file (which is not written unless it is kept).
+    pub python_stdin: bool,
+    /// How the Python script is run.
+    pub python_backend: PythonBackend,
+}
+
+/// How the generated Python script is handled after conversion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "project",
+    derive(serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum RunMode {
+    /// Only write the Python script.
+    #[default]
+    Convert,
+    /// Run the Python script and keep it.
+    Run,
+    /// Run the Python script and delete it after success.
+    RunAndDelete,
+}
+
+impl RunMode {
+    /// Returns the run mode of the `run_python` and `delete_python` flags.
+    ///
+    /// `delete_python` has no effect unless `run_python` is set.
+    pub fn from_flags(run_python: bool, delete_python: bool) -> RunMode {
+        match (run_python, delete_python) {
+            (false, _) => RunMode::Convert,
+            (true, false) => RunMode::Run,
+            (true, true) => RunMode::RunAndDelete,
+        }
+    }
+
+    /// Returns the name of the run mode, as in project configuration files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunMode::Convert => "convert",
+            RunMode::Run => "run",
+            RunMode::RunAndDelete => "run-and-delete",
+        }
+    }
+}
+
+/// How the generated Python script is run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[cfg_attr(
+    feature = "project",
+    derive(serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum PythonBackend {
+    /// Run each script in a new Python process.
+    #[default]
+    Process,
+    /// Run the scripts in long-lived Python worker processes,
+    /// so that the interpreter starts (and library modules are imported) only once.
+    /// Modules imported from the working directory or the `PYTHONPATH` entries are unloaded after each script.
+    ///
+    /// Scripts with resource limits still run in their own process.
+    Worker,
+    /// Run the scripts in a Python interpreter embedded in PyTV (feature `embed-python`),
+    /// one at a time, ignoring the configured interpreter.
+    ///
+    /// A script changes the working directory (`os.chdir`) and the environment (`os.environ`)
+    /// of the host process while it runs, and restores them afterwards.
+    /// Other threads of a program using PyTV as a library see these changes,
+    /// so they must not rely on the working directory, relative paths or the environment
+    /// (nor change them) while a script runs.
+    ///
+    /// Scripts with resource limits still run in their own process.
+    #[cfg(feature = "embed-python")]
+    Embedded,
+}
+
+impl PythonBackend {
+    /// Returns the name of the backend, as on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PythonBackend::Process => "process",
+            PythonBackend::Worker => "worker",
+            #[cfg(feature = "embed-python")]
+            PythonBackend::Embedded => "embedded",
+        }
+    }
+}
+
+/// What happens to the outputs of a previous conversion when a conversion fails.
+///
+/// Outputs are only replaced after a successful conversion (and run of the Python script).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnFailure {
+    /// Keep the previous outputs.
+    #[default]
+    Keep,
+    /// Delete the previous outputs, so that no stale output is used.
+    Delete,
+}
+
+impl OnFailure {
+    /// Returns the name of the policy, as on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OnFailure::Keep => "keep",
+            OnFailure::Delete => "delete",
+        }
+    }
 }
 
 /// Represents the options for input and output file for PyTV.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct FileOptions {
     /// The input file path.
     pub input: String,
     /// The output file path (optional).
     pub output: Option<String>,
+    /// Whether to write the Python script (or the Verilog, if Python is run) to stdout.
+    pub stdout: bool,
+    /// Whether to skip the conversion if the inputs are unchanged since the last one,
+    /// keeping a cache manifest next to the outputs.
+    pub cache: bool,
+    /// Whether to convert even if the cache manifest shows that the outputs are up to date.
+    pub force: bool,
+    /// The depfile listing the output files and the files they depend on (optional).
+    pub depfile: Option<String>,
+    /// Whether the outputs of a previous conversion are kept when the conversion fails.
+    pub on_failure: OnFailure,
 }
 
 impl Default for Config {
@@ -47,16 +193,77 @@ fn parse_key_val(s: &str) -> Result<(String, String), Box<dyn Error>> {
     Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
 }
 
+/// Parse a positive number of seconds
+fn parse_seconds(s: &str) -> Result<std::time::Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0)
+        .and_then(|seconds| std::time::Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds: `{}`", s))
+}
+
+/// Parse a size in bytes, with an optional binary suffix (`K`, `M`, `G` or `T`)
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: `{}`", s))
+}
+
 /// Python Templated Verilog
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Input file
-    #[arg(index = 1, value_name = "FILE")]
-    input: String,
-    /// Output file
+    /// Input files, directories (searched for `*.pytv`) or glob patterns (`-` for stdin)
+    #[arg(index = 1, value_name = "FILE", required = true, num_args = 1..)]
+    inputs: Vec<String>,
+    /// Output file (for a single input)
     #[arg(short, long)]
     output: Option<String>,
+    /// Output directory, mirroring the tree of the input directories
+    #[arg(long = "out-dir", value_name = "DIR", conflicts_with = "output")]
+    out_dir: Option<String>,
+    /// Number of templates converted in parallel (0 for the number of CPUs)
+    #[arg(short = 'j', long = "jobs", value_name = "N", default_value_t = 1)]
+    jobs: usize,
+    /// Write the Python script (or the Verilog, if Python is run) to stdout
+    #[arg(long = "stdout", default_value = "false")]
+    stdout: bool,
+    /// Write a Make-style depfile listing the outputs and the files they depend on
+    #[arg(long = "depfile", value_name = "FILE")]
+    depfile: Option<String>,
+    /// Convert even if the inputs are unchanged since the last conversion
+    #[arg(long = "force", default_value = "false")]
+    force: bool,
+    /// Keep running, converting the templates again when the files they depend on change
+    #[arg(long = "watch", default_value = "false")]
+    watch: bool,
+    #[command(flatten)]
+    template: TemplateArgs,
+    /// Print the effective configuration and where each value comes from, then exit
+    #[arg(long = "print-config", default_value = "false")]
+    print_config: bool,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+/// The command line options about how templates are converted.
+#[derive(clap::Args, Debug)]
+struct TemplateArgs {
     /// Run python (keep Python script)
     #[arg(
         short = 'r',
@@ -82,9 +289,571 @@ struct Args {
     /// Variables (multiple occurrences allowed)
     #[arg(short, long = "var", value_name = "KEY=VAL")]
     vars: Vec<String>,
-    /// Preamble Python file
-    #[arg(short, long = "preamble", value_name = "FILE")]
-    preamble_py: Option<String>,
+    /// Opening delimiter of substituted expressions
+    #[arg(long = "open-delim", default_value = "`", value_name = "STRING")]
+    open_delimiter: String,
+    /// Closing delimiter of substituted expressions
+    #[arg(long = "close-delim", default_value = "`", value_name = "STRING")]
+    close_delimiter: String,
+    /// Preamble Python file (multiple occurrences allowed)
+    #[arg(short = 'p', long = "preamble", value_name = "FILE")]
+    preambles: Vec<String>,
+    /// Include search directory (multiple occurrences allowed)
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    include_paths: Vec<String>,
+    /// Python interpreter (default: `$PYTV_PYTHON`, or `python3`)
+    #[arg(long = "python", value_name = "PATH")]
+    python: Option<String>,
+    /// Directory prepended to `PYTHONPATH` of the script (multiple occurrences allowed)
+    #[arg(long = "python-path", value_name = "DIR")]
+    python_path: Vec<String>,
+    /// Environment variable of the script (multiple occurrences allowed)
+    #[arg(long = "python-env", value_name = "KEY=VAL")]
+    python_env: Vec<String>,
+    /// Working directory of the script (default: the template's directory)
+    #[arg(long = "python-cwd", value_name = "DIR")]
+    python_cwd: Option<String>,
+    /// Pipe the script to Python over stdin (with `-R`, no Python script is written)
+    #[arg(long = "python-stdin", default_value = "false")]
+    python_stdin: bool,
+    /// Skip templates whose inputs are unchanged since the last conversion (implied by `--watch`)
+    #[arg(long = "cache", default_value = "false")]
+    cache: bool,
+    /// Run scripts in a new Python process each, or in persistent worker processes
+    #[arg(long = "python-backend", value_enum, default_value_t = PythonBackend::Process)]
+    python_backend: PythonBackend,
+    /// Keep or delete the previous outputs when a conversion fails
+    #[arg(long = "on-failure", value_enum, default_value_t = OnFailure::Keep)]
+    on_failure: OnFailure,
+    /// Kill the script after SECONDS of wall-clock time
+    #[arg(long = "timeout", value_name = "SECONDS", value_parser = parse_seconds)]
+    timeout: Option<std::time::Duration>,
+    /// Limit the CPU time of the script (Linux only)
+    #[arg(long = "cpu-limit", value_name = "SECONDS")]
+    cpu_limit: Option<u64>,
+    /// Limit the address space of the script, e.g. `2G` (Linux only)
+    #[arg(long = "memory-limit", value_name = "SIZE", value_parser = parse_size)]
+    memory_limit: Option<u64>,
+    /// Limit the size of the Verilog written by the script, e.g. `64M`
+    #[arg(long = "output-limit", value_name = "SIZE", value_parser = parse_size)]
+    output_limit: Option<u64>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum CliCommand {
+    /// Write a Ninja build file or a Makefile fragment converting the templates of a project
+    Buildgen(BuildgenArgs),
+}
+
+/// The command line options of `pytv buildgen`.
+#[derive(clap::Args, Debug)]
+pub(crate) struct BuildgenArgs {
+    /// Input files, directories (searched for `*.pytv`) or glob patterns
+    #[arg(value_name = "FILE", default_value = ".")]
+    pub(crate) inputs: Vec<String>,
+    /// Format of the build file
+    #[arg(long, value_enum, default_value_t = BuildFormat::Ninja)]
+    pub(crate) format: BuildFormat,
+    /// Output build file (stdout if not given)
+    #[arg(short, long)]
+    pub(crate) output: Option<String>,
+    /// Output directory, mirroring the tree of the input directories
+    #[arg(long = "out-dir", value_name = "DIR")]
+    pub(crate) out_dir: Option<String>,
+    /// Command running PyTV in the build file
+    #[arg(long = "pytv", default_value = "pytv", value_name = "COMMAND")]
+    pub(crate) pytv: String,
+    #[command(flatten)]
+    template: TemplateArgs,
+}
+
+/// The parsed command line.
+pub(crate) enum ParsedArgs {
+    /// Convert templates.
+    Convert(Settings, BatchArgs),
+    /// Write a build file (`pytv buildgen`).
+    Buildgen(Settings, Box<BuildgenArgs>),
+}
+
+/// Origin of a configuration value given on the command line.
+const CLI_ORIGIN: &str = "command line";
+/// Origin of a default configuration value.
+const DEFAULT_ORIGIN: &str = "default";
+/// The environment variable selecting the Python interpreter.
+pub(crate) const PYTHON_ENV_VAR: &str = "PYTV_PYTHON";
+
+/// A configuration value and where it comes from.
+#[derive(Debug, Clone)]
+struct Setting<T> {
+    value: T,
+    origin: String,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T, origin: &str) -> Self {
+        Setting {
+            value,
+            origin: origin.to_string(),
+        }
+    }
+
+    /// Replaces a default value with the one from `origin`.
+    #[cfg(feature = "project")]
+    fn set_default(&mut self, value: Option<T>, origin: &str) {
+        if let Some(value) = value.filter(|_| self.origin == DEFAULT_ORIGIN) {
+            *self = Setting::new(value, origin);
+        }
+    }
+}
+
+/// The command line options about the set of inputs, which are not per template.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchArgs {
+    /// Input files, directories or glob patterns.
+    pub(crate) inputs: Vec<String>,
+    /// Output directory, mirroring the tree of the input directories.
+    pub(crate) out_dir: Option<String>,
+    /// Number of templates converted in parallel.
+    pub(crate) jobs: usize,
+    /// Whether to convert again when the inputs change.
+    pub(crate) watch: bool,
+}
+
+/// The command line options merged with the project configuration file.
+#[derive(Debug, Clone)]
+pub(crate) struct Settings {
+    input: String,
+    stdout: bool,
+    force: bool,
+    depfile: Option<String>,
+    print_config: bool,
+    project_file: Option<String>,
+    magic: Setting<String>,
+    tab_size: Setting<u32>,
+    open_delimiter: Setting<String>,
+    close_delimiter: Setting<String>,
+    run_mode: Setting<RunMode>,
+    preambles: Setting<Vec<String>>,
+    include_paths: Setting<Vec<String>>,
+    output: Setting<Option<String>>,
+    python: Setting<Option<String>>,
+    python_path: Setting<Vec<String>>,
+    python_env: Vec<Setting<(String, String)>>,
+    python_cwd: Setting<Option<String>>,
+    python_stdin: Setting<bool>,
+    cache: Setting<bool>,
+    python_backend: Setting<PythonBackend>,
+    limits: Setting<ResourceLimits>,
+    on_failure: Setting<OnFailure>,
+    vars: Vec<Setting<(String, String)>>,
+}
+
+impl Settings {
+    /// Creates the settings from the options about how templates are converted,
+    /// which are parsed into `matches`.
+    fn from_args(args: &TemplateArgs, matches: &clap::ArgMatches) -> Settings {
+        let key_vals = |pairs: &[String]| {
+            pairs
+                .iter()
+                .map(|s| parse_key_val(s).map(|pair| Setting::new(pair, CLI_ORIGIN)))
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()
+                .unwrap_or_else(|err| {
+                    exit_with(PytvError::Config {
+                        span: Span::default(),
+                        message: err.to_string(),
+                    })
+                })
+        };
+        let python = match (&args.python, std::env::var(PYTHON_ENV_VAR)) {
+            (Some(python), _) => Setting::new(Some(python.clone()), CLI_ORIGIN),
+            (None, Ok(python)) if !python.is_empty() => {
+                Setting::new(Some(python), &format!("environment ({PYTHON_ENV_VAR})"))
+            }
+            _ => Setting::new(None, DEFAULT_ORIGIN),
+        };
+        let origin = |id: &str| match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => CLI_ORIGIN,
+            _ => DEFAULT_ORIGIN,
+        };
+        let run_mode = match (args.run_python, args.run_python_del) {
+            (_, true) => Setting::new(RunMode::RunAndDelete, CLI_ORIGIN),
+            (true, _) => Setting::new(RunMode::Run, CLI_ORIGIN),
+            _ => Setting::new(RunMode::Convert, DEFAULT_ORIGIN),
+        };
+        Settings {
+            input: String::new(),
+            stdout: false,
+            force: false,
+            depfile: None,
+            print_config: false,
+            project_file: None,
+            magic: Setting::new(args.magic.clone(), origin("magic")),
+            tab_size: Setting::new(args.tab_size, origin("tab_size")),
+            open_delimiter: Setting::new(args.open_delimiter.clone(), origin("open_delimiter")),
+            close_delimiter: Setting::new(args.close_delimiter.clone(), origin("close_delimiter")),
+            run_mode,
+            preambles: Setting::new(args.preambles.clone(), origin("preambles")),
+            include_paths: Setting::new(args.include_paths.clone(), origin("include_paths")),
+            output: Setting::new(None, DEFAULT_ORIGIN),
+            python,
+            python_path: Setting::new(args.python_path.clone(), origin("python_path")),
+            python_env: key_vals(&args.python_env),
+            python_cwd: Setting::new(args.python_cwd.clone(), origin("python_cwd")),
+            python_stdin: Setting::new(args.python_stdin, origin("python_stdin")),
+            cache: Setting::new(args.cache, origin("cache")),
+            python_backend: Setting::new(args.python_backend, origin("python_backend")),
+            limits: limits_setting(ResourceLimits {
+                timeout: args.timeout,
+                cpu_time: args.cpu_limit,
+                memory: args.memory_limit,
+                output_size: args.output_limit,
+            }),
+            on_failure: Setting::new(args.on_failure, origin("on_failure")),
+            vars: key_vals(&args.vars),
+        }
+    }
+
+    /// Returns the settings of an input, with the defaults of its project configuration file.
+    ///
+    /// `output` (e.g. in the output directory) replaces the output file.
+    pub(crate) fn for_input(&self, input: &str, output: Option<String>) -> PytvResult<Settings> {
+        #[allow(unused_mut)]
+        let mut settings = Settings {
+            input: input.to_string(),
+            ..self.clone()
+        };
+        if output.is_some() {
+            settings.output = Setting::new(output, CLI_ORIGIN);
+        }
+        #[cfg(feature = "project")]
+        if let Some(path) = ProjectConfig::discover(std::path::Path::new(input)) {
+            settings.apply_project(&ProjectConfig::load(&path)?);
+        }
+        Ok(settings)
+    }
+
+    /// Returns whether an output file is given on the command line.
+    pub(crate) fn has_output(&self) -> bool {
+        self.output.value.is_some()
+    }
+
+    /// Returns the project configuration file applied, if any.
+    pub(crate) fn project_file(&self) -> Option<&str> {
+        self.project_file.as_deref()
+    }
+
+    /// Returns whether `--print-config` is given.
+    pub(crate) fn print_config(&self) -> bool {
+        self.print_config
+    }
+
+    /// Uses the values of the project configuration file for the options not given.
+    #[cfg(feature = "project")]
+    fn apply_project(&mut self, project: &ProjectConfig) {
+        let file = project.path.to_string_lossy().to_string();
+        let resolve_all = |paths: &[String]| {
+            (!paths.is_empty()).then(|| paths.iter().map(|p| project.resolve(p)).collect())
+        };
+        self.magic.set_default(project.magic.clone(), &file);
+        self.tab_size.set_default(project.tab_size, &file);
+        self.run_mode.set_default(project.run, &file);
+        self.preambles
+            .set_default(resolve_all(&project.preambles), &file);
+        self.include_paths
+            .set_default(resolve_all(&project.include_dirs), &file);
+        if let Some(dir) = project.output_dir.as_ref().filter(|_| self.input != "-") {
+            let derived = crate::Convert::output_paths(std::path::Path::new(&self.input), None);
+            let file_name = derived.verilog_file.file_name().unwrap_or_default();
+            let output = std::path::Path::new(&project.resolve(dir)).join(file_name);
+            let output = output.to_string_lossy().replace("\\", "/");
+            self.output.set_default(Some(Some(output)), &file);
+        }
+        if let Some(python) = &project.python {
+            // a path (not a command name) is relative to the configuration file
+            let python = if python.contains(['/', '\\']) {
+                project.resolve(python)
+            } else {
+                python.clone()
+            };
+            self.python.set_default(Some(Some(python)), &file);
+        }
+        self.python_path
+            .set_default(resolve_all(&project.python_path), &file);
+        if let Some(dir) = &project.python_cwd {
+            self.python_cwd
+                .set_default(Some(Some(project.resolve(dir))), &file);
+        }
+        self.python_stdin.set_default(project.python_stdin, &file);
+        self.cache.set_default(project.cache, &file);
+        self.python_backend
+            .set_default(project.python_backend, &file);
+        let file_vars = project
+            .vars()
+            .into_iter()
+            .filter(|(name, _)| !self.vars.iter().any(|var| &var.value.0 == name))
+            .map(|var| Setting::new(var, &file))
+            .collect::<Vec<_>>();
+        self.vars.splice(0..0, file_vars);
+        let file_env = project
+            .python_env
+            .iter()
+            .filter(|(name, _)| !self.python_env.iter().any(|var| &var.value.0 == *name))
+            .map(|(name, value)| Setting::new((name.clone(), value.clone()), &file))
+            .collect::<Vec<_>>();
+        self.python_env.splice(0..0, file_env);
+        self.project_file = Some(file);
+    }
+
+    /// Prints the effective configuration, with the origin of each value.
+    pub(crate) fn print(&self) {
+        println!("# input: {}", self.input);
+        println!(
+            "# project configuration file: {}",
+            self.project_file.as_deref().unwrap_or("(none)")
+        );
+        let print = |key: &str, value: String, origin: &str| {
+            println!("{key} = {value}  # {origin}");
+        };
+        let optional = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map_or("(none)".to_string(), |value| format!("{value:?}"))
+        };
+        print(
+            "magic",
+            format!("{:?}", self.magic.value),
+            &self.magic.origin,
+        );
+        print(
+            "tab_size",
+            self.tab_size.value.to_string(),
+            &self.tab_size.origin,
+        );
+        print(
+            "open_delimiter",
+            format!("{:?}", self.open_delimiter.value),
+            &self.open_delimiter.origin,
+        );
+        print(
+            "close_delimiter",
+            format!("{:?}", self.close_delimiter.value),
+            &self.close_delimiter.origin,
+        );
+        print(
+            "run",
+            format!("{:?}", self.run_mode.value.as_str()),
+            &self.run_mode.origin,
+        );
+        print(
+            "preambles",
+            format!("{:?}", self.preambles.value),
+            &self.preambles.origin,
+        );
+        print(
+            "include_dirs",
+            format!("{:?}", self.include_paths.value),
+            &self.include_paths.origin,
+        );
+        print("output", optional(&self.output.value), &self.output.origin);
+        print("python", optional(&self.python.value), &self.python.origin);
+        print(
+            "python_path",
+            format!("{:?}", self.python_path.value),
+            &self.python_path.origin,
+        );
+        print(
+            "python_cwd",
+            optional(&self.python_cwd.value),
+            &self.python_cwd.origin,
+        );
+        for var in &self.python_env {
+            let (name, value) = &var.value;
+            print(
+                &format!("python_env.{name}"),
+                format!("{value:?}"),
+                &var.origin,
+            );
+        }
+        print(
+            "python_stdin",
+            self.python_stdin.value.to_string(),
+            &self.python_stdin.origin,
+        );
+        print("cache", self.cache.value.to_string(), &self.cache.origin);
+        print(
+            "python_backend",
+            format!("{:?}", self.python_backend.value.as_str()),
+            &self.python_backend.origin,
+        );
+        print(
+            "on_failure",
+            format!("{:?}", self.on_failure.value.as_str()),
+            &self.on_failure.origin,
+        );
+        for (key, value) in self.limit_values() {
+            match value {
+                Some(value) => print(key, value, &self.limits.origin),
+                None => print(key, "(none)".to_string(), DEFAULT_ORIGIN),
+            }
+        }
+        for var in &self.vars {
+            let (name, value) = &var.value;
+            print(&format!("vars.{name}"), format!("{value:?}"), &var.origin);
+        }
+    }
+
+    /// Returns the command line options reproducing the settings which are not defaults,
+    /// except the input and output files.
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut push = |flag: &str, value: String, origin: &str| {
+            if origin != DEFAULT_ORIGIN {
+                args.push(flag.to_string());
+                args.push(value);
+            }
+        };
+        push("-m", self.magic.value.clone(), &self.magic.origin);
+        push("-t", self.tab_size.value.to_string(), &self.tab_size.origin);
+        let (open, close) = (&self.open_delimiter, &self.close_delimiter);
+        push("--open-delim", open.value.clone(), &open.origin);
+        push("--close-delim", close.value.clone(), &close.origin);
+        for preamble in &self.preambles.value {
+            push("-p", preamble.clone(), &self.preambles.origin);
+        }
+        for dir in &self.include_paths.value {
+            push("-I", dir.clone(), &self.include_paths.origin);
+        }
+        for var in &self.vars {
+            let (name, value) = &var.value;
+            push("-v", format!("{name}={value}"), &var.origin);
+        }
+        if let Some(python) = &self.python.value {
+            push("--python", python.clone(), &self.python.origin);
+        }
+        for dir in &self.python_path.value {
+            push("--python-path", dir.clone(), &self.python_path.origin);
+        }
+        for var in &self.python_env {
+            let (name, value) = &var.value;
+            push("--python-env", format!("{name}={value}"), &var.origin);
+        }
+        if let Some(dir) = &self.python_cwd.value {
+            push("--python-cwd", dir.clone(), &self.python_cwd.origin);
+        }
+        let backend = &self.python_backend;
+        push(
+            "--python-backend",
+            backend.value.as_str().to_string(),
+            &backend.origin,
+        );
+        let on_failure = &self.on_failure;
+        push(
+            "--on-failure",
+            on_failure.value.as_str().to_string(),
+            &on_failure.origin,
+        );
+        for (key, value) in self.limit_values() {
+            if let Some(value) = value {
+                push(
+                    &format!("--{}", key.replace('_', "-")),
+                    value,
+                    &self.limits.origin,
+                );
+            }
+        }
+        if self.python_stdin.value && self.python_stdin.origin != DEFAULT_ORIGIN {
+            args.push("--python-stdin".to_string());
+        }
+        if self.cache.value && self.cache.origin != DEFAULT_ORIGIN {
+            args.push("--cache".to_string());
+        }
+        match self.run_mode.value {
+            RunMode::Convert => {}
+            RunMode::Run => args.push("-r".to_string()),
+            RunMode::RunAndDelete => args.push("-R".to_string()),
+        }
+        args
+    }
+
+    /// Returns the limits of the Python script by key, as given on the command line.
+    fn limit_values(&self) -> [(&'static str, Option<String>); 4] {
+        let limits = &self.limits.value;
+        [
+            (
+                "timeout",
+                limits.timeout.map(|t| t.as_secs_f64().to_string()),
+            ),
+            ("cpu_limit", limits.cpu_time.map(|s| s.to_string())),
+            ("memory_limit", limits.memory.map(|b| b.to_string())),
+            ("output_limit", limits.output_size.map(|b| b.to_string())),
+        ]
+    }
+
+    /// Makes the Python script run, deleting it unless it is kept explicitly.
+    pub(crate) fn run_python(&mut self) {
+        if self.run_mode.value == RunMode::Convert {
+            self.run_mode = Setting::new(RunMode::RunAndDelete, DEFAULT_ORIGIN);
+        }
+    }
+
+    pub(crate) fn into_builder(self) -> PytvResult<ConvertBuilder> {
+        let mut builder = ConvertBuilder::default()
+            .input(self.input)
+            .magic(self.magic.value)
+            .delimiters(self.open_delimiter.value, self.close_delimiter.value)
+            .run_mode(self.run_mode.value)
+            .tab_size(self.tab_size.value)
+            .stdout(self.stdout)
+            .cache(self.cache.value)
+            .force(self.force);
+        for dir in self.include_paths.value {
+            builder = builder.include_path(dir);
+        }
+        for preamble in self.preambles.value {
+            builder = builder.preamble(preamble);
+        }
+        for var in self.vars {
+            builder = builder.var(var.value.0, var.value.1);
+        }
+        if let Some(output) = self.output.value {
+            builder = builder.output(output);
+        }
+        if let Some(depfile) = self.depfile {
+            builder = builder.depfile(depfile);
+        }
+        if let Some(python) = self.python.value {
+            builder = builder.python(python);
+        }
+        for dir in self.python_path.value {
+            builder = builder.python_path(dir);
+        }
+        for var in self.python_env {
+            builder = builder.python_env(var.value.0, var.value.1);
+        }
+        if let Some(dir) = self.python_cwd.value {
+            builder = builder.python_cwd(dir);
+        }
+        Ok(builder
+            .python_stdin(self.python_stdin.value)
+            .python_backend(self.python_backend.value)
+            .limits(self.limits.value)
+            .on_failure(self.on_failure.value))
+    }
+}
+
+/// Returns the limits given on the command line, with their origin.
+fn limits_setting(limits: ResourceLimits) -> Setting<ResourceLimits> {
+    let origin = match limits.is_empty() {
+        true => DEFAULT_ORIGIN,
+        false => CLI_ORIGIN,
+    };
+    Setting::new(limits, origin)
+}
+
+/// Prints the error and exits with its exit code.
+pub(crate) fn exit_with(err: PytvError) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(err.exit_code());
 }
 
 impl Config {
@@ -105,49 +874,198 @@ impl Config {
         delete_python: bool,
         tab_size: u32,
     ) -> Config {
-        Config {
-            magic_comment_str,
-            template_re,
-            run_python,
-            delete_python,
-            tab_size,
+        Self::builder()
+            .magic(magic_comment_str)
+            .template_re(template_re)
+            .run_mode(RunMode::from_flags(run_python, delete_python))
+            .tab_size(tab_size)
+            .build_unchecked()
+    }
+
+    /// Returns a builder of `Config` with default values (see [`ConfigBuilder`]).
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Returns whether the Python script is run, and whether it is deleted afterwards.
+    pub fn run_mode(&self) -> RunMode {
+        self.run_mode
+    }
+
+    /// Returns `true` if the Python script is run.
+    pub fn run_python(&self) -> bool {
+        self.run_mode != RunMode::Convert
+    }
+
+    /// Returns `true` if the Python script is deleted after it runs.
+    pub fn delete_python(&self) -> bool {
+        self.run_mode == RunMode::RunAndDelete
+    }
+
+    /// Creates a new `Config` instance with the specified substitution delimiters.
+    ///
+    /// Returns an error if the delimiters are invalid (see [`Config::check_delimiters`]).
+    ///
+    /// # Example
+    /// ```
+    /// use pytv::Config;
+    /// let config = Config::with_delimiters("!".to_string(), "${", "}", false, false, 4).unwrap();
+    /// assert!(config.template_re.is_match("wire ${name};"));
+    /// ```
+    pub fn with_delimiters(
+        magic_comment_str: String,
+        open_delimiter: &str,
+        close_delimiter: &str,
+        run_python: bool,
+        delete_python: bool,
+        tab_size: u32,
+    ) -> PytvResult<Config> {
+        Self::builder()
+            .magic(magic_comment_str)
+            .delimiters(open_delimiter, close_delimiter)
+            .run_mode(RunMode::from_flags(run_python, delete_python))
+            .tab_size(tab_size)
+            .build()
+    }
+
+    /// Checks that the delimiters are not empty and do not collide with the magic comment string.
+    pub fn check_delimiters(&self) -> PytvResult<()> {
+        let magic = &self.magic_comment_str;
+        for delimiter in [&self.open_delimiter, &self.close_delimiter] {
+            let message = if delimiter.is_empty() {
+                "Delimiters must not be empty.".to_string()
+            } else if delimiter.chars().any(char::is_whitespace) {
+                format!("Delimiter `{}` must not contain whitespace.", delimiter)
+            } else if delimiter.contains(magic.as_str()) || magic.contains(delimiter.as_str()) {
+                format!(
+                    "Delimiter `{}` collides with the magic comment string `{}`.",
+                    delimiter, magic
+                )
+            } else {
+                continue;
+            };
+            return Err(PytvError::Config {
+                span: Span::default(),
+                message,
+            });
         }
+        Ok(())
     }
 
-    /// Parses the command line arguments and returns a tuple of `Config` and `FileOptions`.
+    /// Parses the command line arguments and returns a tuple of `Config` and `FileOptions`,
+    /// user-defined variables and the preamble.
+    ///
+    /// Defaults are read from the project configuration file (see [`Config::args_builder`]).
+    /// Only the first preamble is returned; use [`Convert::from_args`](crate::Convert::from_args) for all of them.
+    #[allow(clippy::type_complexity)]
     pub fn from_args() -> (
         Config,
         FileOptions,
         Option<Vec<(String, String)>>,
         Option<String>,
     ) {
-        let args = Args::parse();
-        let vars = args
-            .vars
-            .iter()
-            .map(|s| parse_key_val(s))
-            .collect::<Result<Vec<(String, String)>, Box<dyn Error>>>();
-        if vars.is_err() {
-            eprintln!("Error: {}", vars.err().unwrap());
-            std::process::exit(1);
-        }
+        let convert = Self::args_builder()
+            .build()
+            .unwrap_or_else(|err| exit_with(err));
         (
-            Self::new(
-                args.magic,
-                Self::default_template_re(),
-                args.run_python || args.run_python_del,
-                args.run_python_del && !args.run_python,
-                args.tab_size,
-            ),
-            FileOptions {
-                input: args.input,
-                output: args.output,
-            },
-            vars.ok(),
-            args.preamble_py,
+            convert.config,
+            convert.file_options,
+            Some(convert.vars),
+            convert.preambles.into_iter().next(),
         )
     }
 
+    /// Parses the command line arguments into a `ConvertBuilder` for a single input.
+    ///
+    /// With the `project` feature, the nearest `pytv.toml` (or `.pytv.yaml`) found
+    /// by walking up from the input file provides the defaults (see [`ProjectConfig`]),
+    /// which are overridden by command line options.
+    /// With `--print-config`, the effective configuration is printed and the process exits.
+    /// See [`Batch::from_args`](crate::Batch::from_args) for multiple inputs.
+    pub fn args_builder() -> ConvertBuilder {
+        let (settings, batch_args) = Self::parse_args();
+        let [input] = batch_args.inputs.as_slice() else {
+            exit_with(PytvError::Config {
+                span: Span::default(),
+                message: "Exactly one input file is expected.".to_string(),
+            });
+        };
+        let output = batch_args.out_dir.map(|dir| {
+            let base = std::path::Path::new(input)
+                .parent()
+                .unwrap_or(std::path::Path::new(""));
+            crate::batch::mirror_output(std::path::Path::new(input), base, dir.as_ref())
+        });
+        let settings = settings
+            .for_input(input, output)
+            .unwrap_or_else(|err| exit_with(err));
+        if settings.print_config {
+            settings.print();
+            std::process::exit(0);
+        }
+        settings.into_builder().unwrap_or_else(|err| exit_with(err))
+    }
+
+    /// Parses the command line arguments into the settings shared by all inputs
+    /// and the options about the set of inputs.
+    ///
+    /// Exits with an error for subcommands (see [`Config::parse_command_line`]).
+    pub(crate) fn parse_args() -> (Settings, BatchArgs) {
+        match Self::parse_command_line() {
+            ParsedArgs::Convert(settings, batch_args) => (settings, batch_args),
+            ParsedArgs::Buildgen(..) => exit_with(PytvError::Config {
+                span: Span::default(),
+                message: "Subcommand `buildgen` is not supported here.".to_string(),
+            }),
+        }
+    }
+
+    /// Parses the command line arguments, with an optional subcommand.
+    pub(crate) fn parse_command_line() -> ParsedArgs {
+        let matches = Args::command().get_matches();
+        let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        if let Some(CliCommand::Buildgen(buildgen_args)) = args.command {
+            let matches = matches.subcommand_matches("buildgen").unwrap();
+            let settings = Settings::from_args(&buildgen_args.template, matches);
+            return ParsedArgs::Buildgen(settings, Box::new(buildgen_args));
+        }
+        let batch_args = BatchArgs {
+            inputs: args.inputs.clone(),
+            out_dir: args.out_dir.clone(),
+            jobs: args.jobs,
+            watch: args.watch,
+        };
+        if batch_args.inputs.len() > 1 && batch_args.inputs.iter().any(|input| input == "-") {
+            exit_with(PytvError::Config {
+                span: Span::default(),
+                message: "Stdin (`-`) cannot be combined with other inputs.".to_string(),
+            });
+        }
+        if batch_args.watch && batch_args.inputs == ["-"] {
+            exit_with(PytvError::Config {
+                span: Span::default(),
+                message: "Stdin (`-`) cannot be watched.".to_string(),
+            });
+        }
+        let output_origin = match matches.value_source("output") {
+            Some(ValueSource::CommandLine) => CLI_ORIGIN,
+            _ => DEFAULT_ORIGIN,
+        };
+        let mut settings = Settings {
+            stdout: args.stdout,
+            force: args.force,
+            depfile: args.depfile,
+            print_config: args.print_config,
+            output: Setting::new(args.output, output_origin),
+            ..Settings::from_args(&args.template, &matches)
+        };
+        if batch_args.watch {
+            // only the templates whose inputs changed are converted again
+            settings.cache = Setting::new(true, CLI_ORIGIN);
+        }
+        ParsedArgs::Convert(settings, batch_args)
+    }
+
     /// Returns the default magic comment string used to identify template sections in the input file.
     ///
     /// # Example
@@ -169,6 +1087,24 @@ impl Config {
     /// assert_eq!(re.is_match("`test`"), true);
     /// ```
     pub fn default_template_re() -> Regex {
-        Regex::new(r"`([^`]+)`").unwrap()
+        Self::template_re_for("`", "`")
+    }
+
+    /// Returns the regular expression matching expressions between the given delimiters.
+    ///
+    /// # Example
+    /// ```
+    /// use pytv::Config;
+    /// assert_eq!(Config::template_re_for("`", "`").as_str(), r"`([^`]+)`");
+    /// assert_eq!(Config::template_re_for("{{", "}}").as_str(), r"\{\{(.+?)\}\}");
+    /// ```
+    pub fn template_re_for(open_delimiter: &str, close_delimiter: &str) -> Regex {
+        let open = regex::escape(open_delimiter);
+        let close = regex::escape(close_delimiter);
+        if open_delimiter == close_delimiter && open_delimiter.chars().count() == 1 {
+            Regex::new(&format!("{open}([^{close}]+){close}")).unwrap()
+        } else {
+            Regex::new(&format!("{open}(.+?){close}")).unwrap()
+        }
     }
 }
@@ -1,10 +1,20 @@
+use crate::builder::ConvertBuilder;
+use crate::cache::{replace_if_changed, temp_file, write_if_changed, write_temp_file};
+use crate::config::{OnFailure, PythonBackend};
+use crate::depfile::DEPS_FILE_ENV;
+use crate::error::{Error, Result, Span};
+#[cfg(feature = "inst")]
+use crate::inst::InstBlock;
+use crate::pytoken::LogicalLine;
+use crate::scope::ScopeStack;
+use crate::source_map::{MappedWriter, SourceMap, UTILITY_FILE, VARS_FILE};
+use crate::substitution;
+use crate::worker;
 use crate::Config;
 use crate::FileOptions;
-use std::error::Error;
 use std::io::{Result as IoResult, Write};
 use std::path;
 use std::path::PathBuf;
-use std::result::Result;
 
 /// Represents a converter that converts PyTV script to Python script to generate Verilog.
 ///
@@ -12,14 +22,33 @@ use std::result::Result;
 /// It contains methods for converting code and managing input/output files.
 #[derive(Debug, Default)]
 pub struct Convert {
-    config: Config,
-    file_options: FileOptions,
-    vars: Option<Vec<(String, String)>>,
-    preamble_py: Option<String>,
+    pub(crate) config: Config,
+    pub(crate) file_options: FileOptions,
+    pub(crate) vars: Vec<(String, String)>,
+    pub(crate) preambles: Vec<String>,
+    /// The input source, if it is not read from the input file.
+    pub(crate) source: Option<String>,
 }
 
+/// The file name of the input read from stdin, used for diagnostics.
+pub(crate) const STDIN_FILE: &str = "<stdin>";
+/// The template name the outputs of the input read from stdin are named after, unless an output is given.
+const STDIN_OUTPUT_NAME: &str = "stdin.pytv";
+/// The environment variable naming the file the generated script writes the `.inst` contents to,
+/// instead of the `.inst` output file.
+pub(crate) const INST_FILE_ENV: &str = "PYTV_INST_FILE";
+/// The file the `.inst` contents are written to when they are discarded.
+#[cfg(not(target_family = "windows"))]
+const NULL_DEVICE: &str = "/dev/null";
+#[cfg(target_family = "windows")]
+const NULL_DEVICE: &str = "NUL";
+
 /// Canonical output artifact paths derived from input/output options.
+///
+/// More paths may be added in later versions, so the struct cannot be built outside this crate
+/// (see [`Convert::output_paths`]).
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct OutputPaths {
     /// Output Verilog file path.
     pub verilog_file: PathBuf,
@@ -27,22 +56,20 @@ pub struct OutputPaths {
     pub python_script_file: PathBuf,
     /// Output instantiation metadata path.
     pub inst_file: PathBuf,
+    /// Cache manifest path, recording the hash of the inputs and outputs.
+    pub cache_file: PathBuf,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
 enum LineType {
     Verilog,
     PythonInline,
     PythonBlock(bool), // 'false' if in first line ('/*!'), 'true' otherwise
+    Include(String),   // path in '//! <INCLUDE path>'
+    #[default]
     None,
 }
 
-impl Default for LineType {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 impl Convert {
     /// Creates a new `Convert` instance with the given configuration and file options.
     pub fn new(
@@ -54,40 +81,106 @@ impl Convert {
         Convert {
             config,
             file_options,
-            vars,
-            preamble_py,
+            vars: vars.unwrap_or_default(),
+            preambles: preamble_py.into_iter().collect(),
+            source: None,
         }
     }
 
-    /// Creates a new `Convert` instance by parsing command line arguments.
+    /// Returns a builder of `Convert` with default values (see [`ConvertBuilder`]).
+    pub fn builder() -> ConvertBuilder {
+        ConvertBuilder::default()
+    }
+
+    /// Creates a new `Convert` instance by parsing command line arguments
+    /// (see [`Config::args_builder`]).
+    ///
+    /// The input `-` is read from stdin.
     pub fn from_args() -> Convert {
-        let (config, file_options, vars, preamble_py) = Config::from_args();
-        Convert::new(config, file_options, vars, preamble_py)
+        let convert = Config::args_builder().build().unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            std::process::exit(err.exit_code());
+        });
+        if convert.file_options.input != "-" {
+            return convert;
+        }
+        convert
+            .with_reader(STDIN_FILE, std::io::stdin().lock())
+            .unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                std::process::exit(err.exit_code());
+            })
     }
 
-    /// Opens the input file and reads its contents as a string.
-    fn open_input(&self) -> IoResult<String> {
-        std::fs::read_to_string(&self.file_options.input)
+    /// Uses the given template source instead of reading the input file.
+    ///
+    /// `file_name` replaces the input file name, which is used for diagnostics,
+    /// for resolving included files and for deriving output files (unless an output is given).
+    ///
+    /// # Example
+    /// ```
+    /// use pytv::Convert;
+    /// let convert = Convert::default().with_source("top.pytv", "wire w`1 + 2`;\n");
+    /// let script = convert.render_python_script().unwrap();
+    /// assert!(script.contains("print(f'wire w{1 + 2};')"));
+    /// ```
+    pub fn with_source(mut self, file_name: &str, source: &str) -> Convert {
+        self.file_options.input = file_name.to_string();
+        self.source = Some(source.to_string());
+        self
+    }
+
+    /// Uses the template source read from `reader` instead of reading the input file.
+    ///
+    /// See [`Convert::with_source`] for the meaning of `file_name`.
+    pub fn with_reader<R: std::io::BufRead>(
+        self,
+        file_name: &str,
+        mut reader: R,
+    ) -> Result<Convert> {
+        let mut source = String::new();
+        reader
+            .read_to_string(&mut source)
+            .map_err(|err| Error::io(file_name, err))?;
+        Ok(self.with_source(file_name, &source))
+    }
+
+    /// Reads the input source, from the input file unless it is given.
+    fn open_input(&self) -> Result<String> {
+        match &self.source {
+            Some(source) => Ok(source.clone()),
+            None => std::fs::read_to_string(&self.file_options.input)
+                .map_err(|err| Error::io(&self.file_options.input, err)),
+        }
     }
 
     /// Opens the output Python file and returns a file handle.
     ///
     /// Note: This will overwrite the existing file.
+    /// The output directory is created if it does not exist.
     pub fn open_output(&self) -> IoResult<std::fs::File> {
-        std::fs::File::create(&self.output_python_file_name())
+        let file_name = self.output_python_file_name();
+        if let Some(dir) = path::Path::new(&file_name).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::File::create(file_name)
     }
 
+    /// Returns the output Verilog file: `output`, or `input` with its extension changed to `.v`.
+    ///
+    /// Only the final extension is changed (or `.v` is added if there is none).
+    /// The input read from stdin gives `stdin.v` in the current directory.
     fn derive_output_file_name(input: &str, output: Option<&str>) -> PathBuf {
+        let input = if input == STDIN_FILE {
+            STDIN_OUTPUT_NAME
+        } else {
+            input
+        };
         let output = output.map(|s| s.to_string()).unwrap_or_else(|| {
-            let mut derived = input.to_string();
-            // change extension to .v if there is extension
-            let ext = path::Path::new(&derived).extension().unwrap_or_default();
-            if ext.is_empty() {
-                derived.push_str(".v");
-            } else {
-                derived = derived.replace(ext.to_str().unwrap(), "v");
-            }
-            derived
+            path::Path::new(input)
+                .with_extension("v")
+                .to_string_lossy()
+                .to_string()
         });
         PathBuf::from(output.replace("\\", "/"))
     }
@@ -103,14 +196,47 @@ impl Convert {
         let verilog_file = Self::derive_output_file_name(input, output);
         let python_script_file = PathBuf::from(format!("{}.py", verilog_file.to_string_lossy()));
         let inst_file = PathBuf::from(format!("{}.inst", verilog_file.to_string_lossy()));
+        let cache_file = PathBuf::from(format!("{}.cache", verilog_file.to_string_lossy()));
         OutputPaths {
             verilog_file,
             python_script_file,
             inst_file,
+            cache_file,
+        }
+    }
+
+    /// Returns the output artifact paths of this conversion.
+    pub fn outputs(&self) -> OutputPaths {
+        self.output_paths_from_options()
+    }
+
+    /// Returns the output files written in the configured run mode
+    /// (the Python script unless it is deleted, and the Verilog and `.inst` files if it is run).
+    pub fn output_files(&self) -> Vec<PathBuf> {
+        let outputs = self.outputs();
+        let mut files = Vec::new();
+        if !self.config.delete_python() {
+            files.push(outputs.python_script_file);
+        }
+        if self.config.run_python() {
+            files.push(outputs.verilog_file);
+            if self.keeps_inst() {
+                files.push(outputs.inst_file);
+            }
         }
+        files
+    }
+
+    /// Returns `true` if the `.inst` file is written when the script runs,
+    /// which is not the case when the Verilog goes to stdout without an output file.
+    fn keeps_inst(&self) -> bool {
+        !self.file_options.stdout || self.file_options.output.is_some()
     }
 
     /// Resolves canonical output artifact paths from input/output options.
+    ///
+    /// Without `output`, the Verilog file is `input` with its final extension changed to `.v`
+    /// (e.g. `pytv/top.pytv` becomes `pytv/top.v`).
     pub fn output_paths(input: &path::Path, output: Option<&path::Path>) -> OutputPaths {
         let input = input.to_string_lossy().to_string();
         let output = output.map(|v| v.to_string_lossy().to_string());
@@ -154,9 +280,13 @@ impl Convert {
             _ => {
                 if trimmed_line.starts_with(&format!("/*{}", self.config.magic_comment_str)) {
                     LineType::PythonBlock(false)
-                } else if trimmed_line.starts_with(&format!("//{}", self.config.magic_comment_str))
+                } else if let Some(python) =
+                    trimmed_line.strip_prefix(&format!("//{}", self.config.magic_comment_str))
                 {
-                    LineType::PythonInline
+                    match Self::include_path(python) {
+                        Some(path) => LineType::Include(path),
+                        None => LineType::PythonInline,
+                    }
                 } else {
                     LineType::Verilog
                 }
@@ -164,6 +294,52 @@ impl Convert {
         }
     }
 
+    /// Extracts the Python code of a (pre-processed) Python block line,
+    /// and returns whether the line closes the block.
+    ///
+    /// Code on the opening line (`/*! code`) is indented as the opening marker,
+    /// and code on the closing line (`code */`) keeps its indentation.
+    fn python_block_code(&self, line: &str, first_line: bool) -> (String, bool) {
+        let trimmed_line = line.trim_start();
+        let indent = line.len() - trimmed_line.len();
+        let code = if first_line {
+            let code = trimmed_line
+                .strip_prefix(&format!("/*{}", self.config.magic_comment_str))
+                .unwrap_or(trimmed_line)
+                .trim();
+            if code.is_empty() {
+                return (String::new(), false);
+            }
+            format!("{}{}", " ".repeat(indent), code)
+        } else {
+            line.to_string()
+        };
+        // a `*/` in a Python comment or string literal does not close the block
+        let closing = crate::pytoken::strip_comment(&code).ends_with("*/");
+        match code.strip_suffix("*/").filter(|_| closing) {
+            Some(code) if code.trim().is_empty() => (String::new(), true),
+            Some(code) => (code.trim_end().to_string(), true),
+            None => (code, false),
+        }
+    }
+
+    /// Returns the common indentation of the non-empty lines of a Python block,
+    /// given the lines from its opening line.
+    fn python_block_prior(&self, lines: &[&str]) -> usize {
+        let mut prior = None::<usize>;
+        for (idx, line) in lines.iter().enumerate() {
+            let (code, closes) = self.python_block_code(&self.pre_process_line(line), idx == 0);
+            if !code.is_empty() {
+                let indent = code.len() - code.trim_start().len();
+                prior = Some(prior.map_or(indent, |prior| prior.min(indent)));
+            }
+            if closes {
+                break;
+            }
+        }
+        prior.unwrap_or(0)
+    }
+
     /// Pre-processes a line of code by trimming trailing whitespace and replacing tabs with spaces.
     fn pre_process_line(&self, line: &str) -> String {
         line.trim_end().replace(
@@ -186,54 +362,285 @@ impl Convert {
         escaped_line
     }
 
-    /// Applies a regular expression to a line of code based on the template regex in the configuration.
+    /// Substitutes PyTV expressions in a line of Verilog code with `{prefix}{expr}{suffix}`,
+    /// and applies `escape` to the remaining text.
+    ///
+    /// The delimiter tokenizer is used unless a custom template regex is configured.
+    fn substitute_verilog(
+        &self,
+        line: &str,
+        prefix: &str,
+        suffix: &str,
+        escape: impl Fn(&str) -> String,
+    ) -> String {
+        let config = &self.config;
+        if config.template_re.as_str()
+            == Config::template_re_for(&config.open_delimiter, &config.close_delimiter).as_str()
+        {
+            substitution::substitute(
+                line,
+                &config.open_delimiter,
+                &config.close_delimiter,
+                prefix,
+                suffix,
+                escape,
+            )
+        } else {
+            config
+                .template_re
+                .replace_all(
+                    &escape(line),
+                    format!("{prefix}{{${{1}}}}{suffix}").as_str(),
+                )
+                .to_string()
+        }
+    }
+
+    /// Escapes a line of Verilog code and substitutes PyTV expressions with f-string replacement fields.
     fn apply_verilog_regex(&self, line: &str) -> String {
-        self.config
-            .template_re
-            .replace_all(line, format!("{{$1}}").as_str())
-            .to_string()
+        self.substitute_verilog(line, "", "", |text| self.escape_verilog(text))
     }
 
+    #[cfg(feature = "inst")]
     pub(crate) fn apply_protected_verilog_regex(&self, line: &str) -> String {
-        self.config
-            .template_re
-            .replace_all(
-                line,
-                format!("__LEFT_BRACKET__{{$1}}__RIGHT_BRACKET__").as_str(),
-            )
-            .to_string()
+        self.substitute_verilog(
+            line,
+            "__LEFT_BRACKET__",
+            "__RIGHT_BRACKET__",
+            str::to_string,
+        )
     }
 
     /// Runs the Python code to generate verilog.
     ///
-    /// The command `python3` (`python` for Windows) should be available to call.
-    pub fn run_python(&self) -> IoResult<()> {
+    /// The command `python3` (`python` for Windows) should be available to call,
+    /// unless another Python interpreter is configured.
+    /// The script runs in its working directory (see [`Convert::python_cwd`]),
+    /// with the configured `PYTHONPATH` entries and environment variables.
+    /// It is killed if it exceeds one of the configured limits (see [`ResourceLimits`](crate::ResourceLimits)).
+    /// If the script fails, frames of the Python traceback in the generated script
+    /// are rewritten to point to the corresponding input lines.
+    pub fn run_python(&self) -> Result<()> {
         let py_file = self.output_python_file_name();
+        self.run_python_mapped(None, None, &py_file).map(|_| ())
+    }
+
+    /// Runs the Python script and returns the files it read, if they are tracked.
+    ///
+    /// The script is piped to the interpreter over stdin if `script` is given,
+    /// and read from `py_file` otherwise, which is deleted after success if the outputs say so.
+    fn run_python_mapped(
+        &self,
+        source_map: Option<&SourceMap>,
+        script: Option<&[u8]>,
+        py_file: &str,
+    ) -> Result<Vec<String>> {
         let v_file = self.output_file_name();
-        let v_file_f = std::fs::File::create(&v_file)?;
+        let inst_file = self.output_inst_file_name();
+        // the `.inst` file is written to a temporary file, renamed into place after success
+        let inst_temp = temp_file(&inst_file);
+        let inst_env = match self.keeps_inst() {
+            true => absolute_path(&inst_temp),
+            false => NULL_DEVICE.to_string(),
+        };
+        let mut env = vec![(INST_FILE_ENV, inst_env)];
+        if self.tracks_dependencies() {
+            env.push((DEPS_FILE_ENV, absolute_path(self.runtime_deps_file())));
+        }
+        let forward = self.file_options.stdout;
+        let result = self.execute_python(script, py_file, source_map, forward, env);
+        let runtime_deps = self.take_runtime_deps();
+        let (verilog, inst) = result.inspect_err(|_| {
+            let _ = std::fs::remove_file(&inst_temp);
+        })?;
+        if !self.file_options.stdout {
+            write_if_changed(&v_file, &verilog)?;
+        }
+        if let Some(inst) = inst.filter(|_| self.keeps_inst()) {
+            write_if_changed(&inst_file, &inst)?;
+        } else if path::Path::new(&inst_temp).exists() {
+            replace_if_changed(&inst_temp, &inst_file)?;
+        }
+        if self.config.delete_python() && script.is_none() {
+            std::fs::remove_file(py_file).map_err(|err| Error::io(py_file, err))?;
+        }
+        Ok(runtime_deps)
+    }
+
+    /// Runs the Python script with the extra environment variables `env`,
+    /// piping `script` over stdin if it is given (otherwise `py_file` is run),
+    /// and returns the Verilog it printed.
+    ///
+    /// With the worker and embedded backends (see [`PythonBackend`](crate::PythonBackend)),
+    /// the `.inst` file is captured and returned instead of written.
+    /// The Verilog is also written to stdout if `forward` is set and the script succeeds,
+    /// so that a failed script never sends partial output down a pipe.
+    /// If the script fails, the error points to the input line of the innermost traceback frame.
+    fn execute_python(
+        &self,
+        script: Option<&[u8]>,
+        py_file: &str,
+        source_map: Option<&SourceMap>,
+        forward: bool,
+        env: Vec<(&str, String)>,
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let script_name = match script {
+            Some(_) => STDIN_FILE.to_string(),
+            None => absolute_path(py_file),
+        };
+        let python = self.python_program();
+        let backend = self.python_backend();
+        let (exit_code, stdout, stderr, inst) = if backend != PythonBackend::Process {
+            let file_script;
+            let script = match script {
+                Some(script) => script,
+                None => {
+                    file_script = std::fs::read(py_file).map_err(|err| Error::io(py_file, err))?;
+                    &file_script
+                }
+            };
+            let job = worker::Job {
+                script,
+                file_name: &script_name,
+                cwd: absolute_path(self.python_cwd()),
+                python_path: self.config.python_path.iter().map(absolute_path).collect(),
+                env: (self.config.python_env.iter().cloned())
+                    .chain(
+                        env.into_iter()
+                            .map(|(name, value)| (name.to_string(), value)),
+                    )
+                    .collect(),
+            };
+            let output = match backend {
+                #[cfg(feature = "embed-python")]
+                PythonBackend::Embedded => crate::embed::run(&job),
+                _ => worker::run(&python, &job).map_err(|err| Error::io(&python, err))?,
+            };
+            (
+                output.exit_code,
+                output.stdout,
+                output.stderr,
+                Some(output.inst),
+            )
+        } else {
+            let mut command = self.python_command();
+            command.envs(env);
+            command.arg(if script.is_some() { "-" } else { &script_name });
+            let (output, limit) = self
+                .config
+                .limits
+                .run(command, script.map(<[u8]>::to_vec))
+                .map_err(|err| Error::io(&python, err))?;
+            if let Some(limit) = limit {
+                return Err(Error::Limit {
+                    span: Span::new(&self.file_options.input, 0, 0),
+                    limit,
+                });
+            }
+            (output.status.code(), output.stdout, output.stderr, None)
+        };
+        if exit_code == Some(0) {
+            if forward {
+                std::io::stdout()
+                    .write_all(&stdout)
+                    .map_err(|err| Error::io("<stdout>", err))?;
+            }
+            return Ok((stdout, inst));
+        }
+        let stderr = String::from_utf8_lossy(&stderr);
+        // the error points to the script file if no input line is found
+        let fallback = match script {
+            Some(_) => Span::new(&self.file_options.input, 0, 0),
+            None => Span::new(py_file, 0, 0),
+        };
+        // rebuild the source map if the caller did not keep one
+        let rebuilt_map = match source_map {
+            Some(_) => None,
+            None => self.convert_mapped(std::io::sink()).ok(),
+        };
+        let (span, traceback) = match source_map.or(rebuilt_map.as_ref()) {
+            Some(source_map) => (
+                source_map
+                    .traceback_location(&script_name, &stderr)
+                    .map(|loc| {
+                        let column = loc.text.len() - loc.text.trim_start().len() + 1;
+                        Span::new(&loc.file, loc.line, column)
+                    })
+                    .unwrap_or(fallback),
+                source_map.rewrite_traceback(&script_name, &stderr),
+            ),
+            None => (fallback, stderr.to_string()),
+        };
+        Err(Error::Python {
+            span,
+            exit_code,
+            traceback,
+        })
+    }
+
+    /// Returns how the Python script is run (see [`PythonBackend`](crate::PythonBackend)).
+    ///
+    /// Scripts with resource limits always run in their own process.
+    pub(crate) fn python_backend(&self) -> PythonBackend {
+        match self.config.limits.is_empty() {
+            true => self.config.python_backend,
+            false => PythonBackend::Process,
+        }
+    }
+
+    /// Returns the Python interpreter.
+    fn python_program(&self) -> String {
         #[cfg(not(target_family = "windows"))]
-        let python_cmd = "python3";
+        let default_python_cmd = "python3";
         #[cfg(target_family = "windows")]
-        let python_cmd = "python";
-        let output = std::process::Command::new(python_cmd)
-            .arg(&py_file)
-            .stdout(v_file_f)
-            .output()?;
-        if !output.status.success() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "Python script failed with exit code: {}\n{}",
-                    output.status.code().unwrap_or(-1),
-                    String::from_utf8_lossy(&output.stderr)
-                ),
-            ));
-        } else {
-            if self.config.delete_python {
-                std::fs::remove_file(&py_file)?;
+        let default_python_cmd = "python";
+        let python_cmd = self.config.python.as_deref().unwrap_or(default_python_cmd);
+        // an interpreter path is relative to the current directory, not to the working directory
+        match python_cmd.contains(['/', '\\']) {
+            true => absolute_path(python_cmd),
+            false => python_cmd.to_string(),
+        }
+    }
+
+    /// Returns the command running the Python interpreter
+    /// with the configured `PYTHONPATH`, environment and working directory.
+    fn python_command(&self) -> std::process::Command {
+        let mut command = std::process::Command::new(self.python_program());
+        command.current_dir(self.python_cwd());
+        if !self.config.python_path.is_empty() {
+            let dirs = self
+                .config
+                .python_path
+                .iter()
+                .map(|dir| absolute_path(dir).into());
+            let inherited = std::env::var_os("PYTHONPATH");
+            let paths = dirs.chain(inherited.iter().flat_map(std::env::split_paths));
+            if let Ok(python_path) = std::env::join_paths(paths.collect::<Vec<PathBuf>>()) {
+                command.env("PYTHONPATH", python_path);
             }
         }
-        Ok(())
+        command.envs(
+            self.config
+                .python_env
+                .iter()
+                .map(|(name, value)| (name, value)),
+        );
+        command
+    }
+
+    /// Returns the working directory of the Python script:
+    /// the configured one, or the directory of the template (the current directory for stdin).
+    pub fn python_cwd(&self) -> PathBuf {
+        if let Some(dir) = &self.config.python_cwd {
+            return absolute_path(dir).into();
+        }
+        let input = &self.file_options.input;
+        match path::Path::new(input).parent() {
+            Some(dir) if input != STDIN_FILE && !dir.as_os_str().is_empty() => {
+                absolute_path(dir).into()
+            }
+            _ => std::env::current_dir().unwrap_or_default(),
+        }
     }
 
     #[cfg(not(feature = "inst"))]
@@ -241,179 +648,585 @@ impl Convert {
         &self,
         line: &str,
         py_indent_prior: usize,
+        base_indent: usize,
         stream: &mut W,
     ) -> Result<()> {
-        writeln!(stream, "{}", utf8_slice::from(&line, py_indent_prior))
+        let useful_str = utf8_slice::from(line, py_indent_prior);
+        if useful_str.is_empty() {
+            writeln!(stream)?;
+        } else {
+            writeln!(stream, "{}{useful_str}", " ".repeat(base_indent))?;
+        }
+        Ok(())
     }
 
     #[cfg(feature = "macro")]
-    fn print_macros<W: Write>(&self, stream: &mut W) -> Result<(), Box<dyn Error>> {
+    fn print_macros<W: Write>(&self, stream: &mut MappedWriter<W>) -> IoResult<()> {
         let output_verilog_file_name = &self.output_file_name();
         let output_inst_file_name = &self.output_inst_file_name();
         let verilog_path = path::Path::new(output_verilog_file_name);
         let inst_path = path::Path::new(output_inst_file_name);
-        writeln!(
-            stream,
-            concat!(
-                "# PyTV macros:\n",
-                "OUTPUT_VERILOG_FILE_PATH = '{}'\n",
-                "OUTPUT_VERILOG_FILE_NAME = '{}'\n",
-                "OUTPUT_VERILOG_FILE_STEM = '{}'\n",
-                "OUTPUT_INST_FILE_PATH = '{}'\n",
-                "OUTPUT_INST_FILE_NAME = '{}'\n\n",
+        stream.write_section(
+            crate::source_map::MACRO_FILE,
+            &format!(
+                concat!(
+                    "# PyTV macros:\n",
+                    "OUTPUT_VERILOG_FILE_PATH = '{}'\n",
+                    "OUTPUT_VERILOG_FILE_NAME = '{}'\n",
+                    "OUTPUT_VERILOG_FILE_STEM = '{}'\n",
+                    "OUTPUT_INST_FILE_PATH = '{}'\n",
+                    "OUTPUT_INST_FILE_NAME = '{}'\n\n",
+                ),
+                absolute_path(output_verilog_file_name),
+                verilog_path.file_name().unwrap().to_str().unwrap(),
+                verilog_path.file_stem().unwrap().to_str().unwrap(),
+                absolute_path(output_inst_file_name),
+                inst_path.file_name().unwrap().to_str().unwrap(),
             ),
-            output_verilog_file_name,
-            verilog_path.file_name().unwrap().to_str().unwrap(),
-            verilog_path.file_stem().unwrap().to_str().unwrap(),
-            output_inst_file_name,
-            inst_path.file_name().unwrap().to_str().unwrap(),
         )?;
-        Ok(())
+        writeln!(stream)
     }
 
-    fn update_py_indent_space(&self, line: &str, py_indent_space: usize) -> usize {
-        if !line.is_empty() {
-            let re = regex::Regex::new(r":\s*(#|$)").unwrap();
-            line.chars().position(|c| !c.is_whitespace()).unwrap_or(0)
-                + if re.is_match(line) {
-                    self.config.tab_size as usize
-                } else {
-                    0usize
-                }
-        } else {
+    /// Feeds a Python line to the logical line tracker and returns the indentation
+    /// (relative to `py_indent_prior`) of the Verilog lines following it.
+    ///
+    /// The indentation increases by the tab size after a logical line opening a block,
+    /// and is kept on the continuation lines of a logical line.
+    fn update_py_indent_space(
+        &self,
+        logical: &mut LogicalLine,
+        line: &str,
+        span: &Span,
+        py_indent_prior: usize,
+        py_indent_space: usize,
+    ) -> usize {
+        if line.is_empty() && !logical.is_open() {
+            return py_indent_space;
+        }
+        let continued = logical.is_open();
+        logical.feed(line, span);
+        if logical.opens_block() {
+            logical.indent - py_indent_prior + self.config.tab_size as usize
+        } else if continued {
             py_indent_space
+        } else {
+            logical.indent - py_indent_prior
         }
     }
 
     /// Converts the code and writes the converted code to the given stream.
-    pub fn convert<W: Write>(&self, mut stream: W) -> Result<(), Box<dyn Error>> {
-        let mut first_py_line = false;
-        let mut py_indent_prior = 0usize;
-        let mut py_indent_space = 0usize;
-        let magic_string_len = 2 + self.config.magic_comment_str.len();
-        #[cfg(feature = "inst")]
-        let mut within_inst = false;
-        #[cfg(feature = "inst")]
-        let mut inst_indent_space = 0usize;
-        let mut inst_str = String::new();
-        #[cfg(feature = "inst")]
+    pub fn convert<W: Write>(&self, stream: W) -> Result<()> {
+        self.convert_mapped(stream).map(|_| ())
+    }
+
+    /// Converts the code, writes the converted code to the given stream
+    /// and returns the map from generated script lines to input lines.
+    pub fn convert_mapped<W: Write>(&self, stream: W) -> Result<SourceMap> {
+        let mut stream = MappedWriter::new(stream);
+        self.write_dependency_hook(&mut stream)?;
         // print user-defined variables
-        if let Some(vars) = &self.vars {
-            if !vars.is_empty() {
-                writeln!(stream, "# User-defined variables:")?;
-                for (name, value) in vars {
-                    writeln!(stream, "{} = {}", name, value)?;
-                }
-                writeln!(stream)?;
+        if !self.vars.is_empty() {
+            let mut section = String::from("# User-defined variables:\n");
+            for (name, value) in &self.vars {
+                section.push_str(&format!("{} = {}\n", name, value));
             }
+            stream.write_section(VARS_FILE, &section)?;
+            writeln!(stream)?;
         }
-        // load preamble
-        if let Some(preamble_py) = &self.preamble_py {
+        // load preambles
+        for preamble_py in &self.preambles {
             // read from file and write to stream
-            let preamble_py = std::fs::read_to_string(preamble_py)?;
-            writeln!(stream, "# Preamble:\n{}", preamble_py)?;
+            let preamble =
+                std::fs::read_to_string(preamble_py).map_err(|err| Error::io(preamble_py, err))?;
+            stream.add_input(preamble_py);
+            stream.set_origin(preamble_py, 0, "");
+            writeln!(stream, "# Preamble:")?;
+            for (idx, line) in preamble.lines().enumerate() {
+                stream.set_origin(preamble_py, idx + 1, line);
+                writeln!(stream, "{line}")?;
+            }
+            writeln!(stream)?;
         }
-        writeln!(
-            stream,
-            concat!(
-                "# PyTV utility functions:\n",
-                "_inst_file = open('{}', 'w')\n",
-                "def _inst_var_map(tuples):\n",
-                "    s = ['%s: %s\\n' % tuple for tuple in tuples]\n",
-                "    return '    '.join(s)\n\n",
-                "def _verilog_ports_var_map(tuples, first_port):\n",
-                "    s = ['  .%s(%s)' % tuple for tuple in tuples]\n",
-                "    return ('' if first_port else ',\\n') + ',\\n'.join(s)\n\n",
-                "def _verilog_vparams_var_map(tuples, first_vparam):\n",
-                "    s = ['\\n  .%s(%s)' % tuple for tuple in tuples]\n",
-                "    return ('#(' if first_vparam else ',') + ','.join(s)\n",
+        stream.write_section(
+            UTILITY_FILE,
+            &format!(
+                concat!(
+                    "# PyTV utility functions:\n",
+                    "import os as _os\n",
+                    "_inst_file = open(_os.environ.get('{}', '{}'), 'w')\n",
+                    "def _inst_var_map(tuples):\n",
+                    "    s = ['%s: %s\\n' % tuple for tuple in tuples]\n",
+                    "    return '    '.join(s)\n\n",
+                    "def _verilog_ports_var_map(tuples, first_port):\n",
+                    "    s = ['  .%s(%s)' % tuple for tuple in tuples]\n",
+                    "    return ('' if first_port else ',\\n') + ',\\n'.join(s)\n\n",
+                    "def _verilog_vparams_var_map(tuples, first_vparam):\n",
+                    "    s = ['\\n  .%s(%s)' % tuple for tuple in tuples]\n",
+                    "    return ('#(' if first_vparam else ',') + ','.join(s)\n",
+                ),
+                INST_FILE_ENV,
+                absolute_path(self.output_inst_file_name())
             ),
-            self.output_inst_file_name()
         )?;
+        writeln!(stream)?;
         #[cfg(feature = "macro")]
         self.print_macros(&mut stream)?;
+        let input = &self.file_options.input;
+        let mut include_chain = vec![Self::include_key(path::Path::new(input))];
+        self.convert_source(
+            input,
+            &self.open_input()?,
+            0,
+            &mut include_chain,
+            &mut stream,
+        )?;
+        #[cfg(feature = "inst")]
+        stream.write_section(UTILITY_FILE, "_inst_file.close()")?;
+        Ok(stream.into_source_map())
+    }
+
+    /// Converts the template `source` read from `file` and writes the converted code to the stream.
+    ///
+    /// All generated lines are indented by `base_indent` spaces,
+    /// and `include_chain` holds the files being included to detect cycles.
+    fn convert_source<W: Write>(
+        &self,
+        file: &str,
+        source: &str,
+        base_indent: usize,
+        include_chain: &mut Vec<PathBuf>,
+        stream: &mut MappedWriter<W>,
+    ) -> Result<()> {
+        stream.add_input(file);
+        let mut first_py_line = false;
+        let mut py_indent_prior = 0usize;
+        let mut py_indent_space = 0usize;
+        let magic_string_len = 2 + self.config.magic_comment_str.len();
+        #[cfg(feature = "inst")]
+        let mut inst = InstBlock::default();
+        let mut scopes = ScopeStack::default();
+        let mut logical = LogicalLine::default();
         let mut line_type = LineType::default();
+        // indentation of the enclosing inline scope and common indentation of the Python block
+        let mut block_indent = 0usize;
+        let mut block_prior = 0usize;
+        let mut block_span = Span::default();
+        let lines: Vec<&str> = source.lines().collect();
         // parse line by line
-        for line in self.open_input()?.lines() {
-            let line = self.pre_process_line(&line);
+        for (idx, line) in lines.iter().copied().enumerate() {
+            let span = Span::new(file, idx + 1, 1);
+            #[cfg(feature = "inst")]
+            let within_inst = inst.within;
+            #[cfg(not(feature = "inst"))]
+            let within_inst = false;
+            #[cfg(feature = "inst")]
+            let origin_line = if within_inst {
+                inst.open_span.line
+            } else {
+                idx + 1
+            };
+            #[cfg(not(feature = "inst"))]
+            let origin_line = idx + 1;
+            stream.set_origin(file, origin_line, line);
+            let line = self.pre_process_line(line);
             self.switch_line_type(&mut line_type, line.as_str());
-            match line_type {
-                LineType::PythonBlock(true) => {
-                    py_indent_space = self.update_py_indent_space(&line, py_indent_space);
+            match &line_type {
+                LineType::PythonBlock(not_first_line) => {
+                    let first_line = !not_first_line;
+                    if first_line {
+                        block_indent = py_indent_space;
+                        block_prior = self.python_block_prior(&lines[idx..]);
+                        block_span = span.clone();
+                    }
+                    let (line, closes) = self.python_block_code(&line, first_line);
+                    if closes {
+                        line_type = LineType::None;
+                    }
+                    if line.is_empty() && (first_line || closes) {
+                        continue;
+                    }
+                    if within_inst {
+                        if !line.is_empty() {
+                            let indent = line.len() - line.trim_start().len();
+                            py_indent_space = block_indent + indent - block_prior;
+                        }
+                    } else {
+                        py_indent_space = block_indent
+                            + self.update_py_indent_space(
+                                &mut logical,
+                                &line,
+                                &span,
+                                block_prior,
+                                py_indent_space - block_indent,
+                            );
+                        if !line.is_empty() && !logical.is_open() {
+                            scopes.python_line(
+                                &logical.first_line,
+                                block_indent + logical.indent - block_prior,
+                                logical.opens_block(),
+                                logical.span.clone(),
+                            );
+                        }
+                    }
+                    let base_indent = base_indent + block_indent;
                     #[cfg(feature = "inst")]
                     self.process_python_line(
                         &line,
-                        0,
-                        &mut stream,
-                        &mut within_inst,
-                        &mut inst_str,
-                        &mut inst_indent_space,
+                        block_prior,
+                        base_indent,
+                        stream,
+                        &mut inst,
+                        span,
                     )?;
                     #[cfg(not(feature = "inst"))]
-                    self.process_python_line(&line, 0, &mut stream)?;
+                    self.process_python_line(&line, block_prior, base_indent, stream)?;
                 }
-                LineType::PythonInline => {
+                LineType::PythonInline | LineType::Include(_) => {
+                    let indent = line.len() - line.trim_start().len();
+                    let span = Span {
+                        column: indent + magic_string_len + 1,
+                        ..span
+                    };
                     let line = utf8_slice::from(line.trim_start(), magic_string_len);
                     if !first_py_line && !line.is_empty() {
                         first_py_line = true;
                         py_indent_prior =
                             line.chars().position(|c| !c.is_whitespace()).unwrap_or(0);
                     }
-                    if !utf8_slice::till(&line, py_indent_prior).trim().is_empty() {
-                        Err(format!(
-                            "Python line should start with {} spaces.\nUnexpected line: {}",
-                            py_indent_prior, &line
-                        ))?;
+                    if !utf8_slice::till(line, py_indent_prior).trim().is_empty() {
+                        let column = line.chars().position(|c| !c.is_whitespace()).unwrap_or(0);
+                        return Err(Error::Indentation {
+                            span: Span {
+                                column: span.column + column,
+                                ..span
+                            },
+                            message: format!(
+                                "Python line should start with {} spaces.\nUnexpected line: {}",
+                                py_indent_prior, &line
+                            ),
+                        });
+                    }
+                    let indent = line.len() - line.trim_start().len();
+                    let statement_span = Span {
+                        column: span.column + indent,
+                        ..span.clone()
+                    };
+                    if within_inst {
+                        if !line.is_empty() {
+                            py_indent_space = indent - py_indent_prior;
+                        }
+                    } else if let Some(keyword) =
+                        ScopeStack::end_keyword(line).filter(|_| !logical.is_open())
+                    {
+                        // explicit end of scope, not written to the Python script
+                        py_indent_space = scopes.close(keyword, statement_span)?;
+                        continue;
+                    } else {
+                        py_indent_space = self.update_py_indent_space(
+                            &mut logical,
+                            line,
+                            &statement_span,
+                            py_indent_prior,
+                            py_indent_space,
+                        );
+                        if !line.is_empty() && !logical.is_open() {
+                            scopes.python_line(
+                                &logical.first_line,
+                                logical.indent - py_indent_prior,
+                                logical.opens_block(),
+                                logical.span.clone(),
+                            );
+                        }
+                    }
+                    if let LineType::Include(include) = &line_type {
+                        if logical.is_open() {
+                            return Err(Error::Syntax {
+                                span: statement_span,
+                                message: format!(
+                                    "<INCLUDE> within an unterminated Python statement (started at line {}).",
+                                    logical.span.line
+                                ),
+                            });
+                        }
+                        let column = line.chars().position(|c| !c.is_whitespace()).unwrap_or(0);
+                        let span = Span {
+                            column: span.column + column,
+                            ..span
+                        };
+                        #[cfg(feature = "inst")]
+                        if inst.within {
+                            return Err(Error::Include {
+                                span,
+                                message: "<INCLUDE> is not allowed within <INST>.".to_string(),
+                            });
+                        }
+                        let (included_file, included_source) =
+                            self.open_include(include, include_chain, &span)?;
+                        self.convert_source(
+                            &included_file,
+                            &included_source,
+                            base_indent + py_indent_space,
+                            include_chain,
+                            stream,
+                        )?;
+                        include_chain.pop();
+                        continue;
                     }
-                    py_indent_space =
-                        self.update_py_indent_space(&line, py_indent_space) - py_indent_prior;
                     #[cfg(feature = "inst")]
                     self.process_python_line(
-                        &line,
+                        line,
                         py_indent_prior,
-                        &mut stream,
-                        &mut within_inst,
-                        &mut inst_str,
-                        &mut inst_indent_space,
+                        base_indent,
+                        stream,
+                        &mut inst,
+                        span,
                     )?;
                     #[cfg(not(feature = "inst"))]
-                    self.process_python_line(&line, py_indent_prior, &mut stream)?;
+                    self.process_python_line(line, py_indent_prior, base_indent, stream)?;
                 }
                 LineType::Verilog => {
-                    let line = self.apply_verilog_regex(self.escape_verilog(&line).as_str());
-                    writeln!(stream, "{}print(f'{line}')", " ".repeat(py_indent_space))?;
+                    if logical.is_open() {
+                        return Err(Error::Syntax {
+                            span,
+                            message: format!(
+                                "Verilog line within an unterminated Python statement (started at line {}).",
+                                logical.span.line
+                            ),
+                        });
+                    }
+                    let line = self.apply_verilog_regex(&line);
+                    writeln!(
+                        stream,
+                        "{}print(f'{line}')",
+                        " ".repeat(base_indent + py_indent_space)
+                    )?;
                 }
                 _ => {}
             }
         }
+        // report every construct left open at the end of the input
+        let mut unclosed = scopes.unclosed();
+        let scope_count = unclosed.len();
+        if let LineType::PythonBlock(_) = line_type {
+            let message = format!(
+                "Python block opened at line {} is not closed with `*/`.",
+                block_span.line
+            );
+            unclosed.push((block_span, message));
+        }
         #[cfg(feature = "inst")]
-        writeln!(stream, "_inst_file.close()")?;
-        Ok(())
+        if inst.within {
+            let message = format!(
+                "<INST> opened at line {} is not closed with </INST>.",
+                inst.open_span.line
+            );
+            unclosed.push((inst.open_span, message));
+        }
+        if logical.is_open() {
+            let message = format!(
+                "Python statement started at line {} is not terminated.",
+                logical.span.line
+            );
+            unclosed.push((logical.span, message));
+        }
+        let only_scopes = unclosed.len() == scope_count;
+        unclosed.sort_by_key(|(span, _)| span.line);
+        let Some(span) = unclosed.first().map(|(span, _)| span.clone()) else {
+            return Ok(());
+        };
+        let message = unclosed
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>()
+            .join("\n");
+        Err(if only_scopes {
+            Error::Scope { span, message }
+        } else {
+            Error::Syntax { span, message }
+        })
     }
 
     /// Renders the generated Python script as a string.
-    pub fn render_python_script(&self) -> Result<String, Box<dyn Error>> {
+    pub fn render_python_script(&self) -> Result<String> {
         let mut stream = Vec::<u8>::new();
         self.convert(&mut stream)?;
-        Ok(String::from_utf8(stream)?)
+        String::from_utf8(stream).map_err(|err| {
+            Error::io(
+                &self.file_options.input,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+            )
+        })
+    }
+
+    /// Renders the Verilog as a string, without touching the filesystem.
+    ///
+    /// The generated Python script (see [`Convert::render_python_script`])
+    /// is piped to the interpreter over stdin, like [`Convert::run_python`] does otherwise,
+    /// and the Verilog it prints is returned.
+    /// The `.inst` file the script writes is discarded.
+    ///
+    /// With the embedded backend ([`PythonBackend::Embedded`](crate::PythonBackend)),
+    /// the script runs inside the calling process: while it runs, the working directory
+    /// and the environment variables of the whole process are changed,
+    /// so other threads must not depend on them (or call [`std::env::set_var`]) meanwhile.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use pytv::Convert;
+    /// let verilog = Convert::builder()
+    ///     .input("top.pytv")
+    ///     .build()
+    ///     .unwrap()
+    ///     .render_verilog()
+    ///     .unwrap();
+    /// println!("{verilog}");
+    /// ```
+    pub fn render_verilog(&self) -> Result<String> {
+        let mut script = Vec::new();
+        let source_map = self.convert_mapped(&mut script)?;
+        let env = vec![(INST_FILE_ENV, NULL_DEVICE.to_string())];
+        let py_file = self.output_python_file_name();
+        let (verilog, _) =
+            self.execute_python(Some(&script), &py_file, Some(&source_map), false, env)?;
+        String::from_utf8(verilog).map_err(|err| {
+            Error::io(
+                &self.file_options.input,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+            )
+        })
     }
 
     /// Converts the code and writes the converted code to a file.
     ///
     /// With default `Config`, the output will be a Python file.
-    pub fn convert_to_file(&self) -> Result<(), Box<dyn Error>> {
-        let out_f = self.open_output()?;
-        self.convert(out_f)?;
-        if self.config.run_python {
-            self.run_python()?;
+    /// If stdout output is chosen in `FileOptions`, the Python script is written to stdout,
+    /// or the Verilog is if the Python script is run.
+    /// Output files whose contents are unchanged are not rewritten.
+    ///
+    /// Output files are written to temporary files renamed into place,
+    /// and the Verilog, `.inst` and Python script outputs are only replaced if the Python script succeeds.
+    /// If the conversion fails, the previous outputs are kept or deleted
+    /// as chosen in `FileOptions` (see [`OnFailure`](crate::OnFailure)).
+    pub fn convert_to_file(&self) -> Result<()> {
+        self.update_files().map(|_| ())
+    }
+
+    /// Converts the code and writes the output files like [`Convert::convert_to_file`],
+    /// unless the cache manifest shows that they are up to date (see [`Convert::cache_key`]).
+    ///
+    /// The cache is only used if it is enabled in `FileOptions` and the output is not stdout.
+    /// Returns `false` if the conversion is skipped.
+    pub fn update_files(&self) -> Result<bool> {
+        self.update_files_tracked(&mut Vec::new())
+    }
+
+    /// Like [`Convert::update_files`], and adds the files the outputs depend on to `deps`
+    /// (see [`Convert::dependencies`]), as far as they are known when an error occurs.
+    pub(crate) fn update_files_tracked(&self, deps: &mut Vec<String>) -> Result<bool> {
+        let result = self.write_outputs(deps);
+        if result.is_err()
+            && self.file_options.on_failure == OnFailure::Delete
+            && !self.file_options.stdout
+        {
+            self.remove_outputs();
         }
-        Ok(())
+        result
+    }
+
+    /// Removes the output files of a previous conversion, with its cache manifest.
+    fn remove_outputs(&self) {
+        let outputs = self.outputs();
+        for file in [
+            outputs.python_script_file,
+            outputs.verilog_file,
+            outputs.inst_file,
+            outputs.cache_file,
+        ] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    /// Writes the output files for [`Convert::update_files_tracked`].
+    fn write_outputs(&self, deps: &mut Vec<String>) -> Result<bool> {
+        if self.file_options.input != STDIN_FILE {
+            deps.push(self.file_options.input.clone());
+        }
+        deps.extend(self.preambles.iter().cloned());
+        if self.file_options.stdout && !self.config.run_python() {
+            // an incomplete script must not reach the pipeline
+            let mut script = Vec::new();
+            self.convert(&mut script)?;
+            std::io::stdout()
+                .write_all(&script)
+                .map_err(|err| Error::io("<stdout>", err))?;
+            return Ok(true);
+        }
+        let mut script = Vec::new();
+        let source_map = self.convert_mapped(&mut script)?;
+        deps.extend(self.dependencies(&source_map, &[]));
+        let cache = self.file_options.cache && !self.file_options.stdout;
+        let key = match cache {
+            true => Some(self.cache_key(&script, &source_map)?),
+            false => None,
+        };
+        if let Some(key) = key.as_ref().filter(|_| !self.file_options.force) {
+            if let Some(runtime_deps) = self.up_to_date_deps(key) {
+                deps.extend(runtime_deps.iter().cloned());
+                self.write_depfile(&source_map, &runtime_deps)?;
+                return Ok(false);
+            }
+        }
+        let py_file = self.output_python_file_name();
+        let runtime_deps = if !self.config.run_python() {
+            write_if_changed(&py_file, &script)?;
+            Vec::new()
+        } else if self.config.python_stdin {
+            let runtime_deps =
+                self.run_python_mapped(Some(&source_map), Some(&script), &py_file)?;
+            if !self.config.delete_python() {
+                write_if_changed(&py_file, &script)?;
+            }
+            runtime_deps
+        } else {
+            // the script runs from a temporary file, so that a failure keeps the previous one
+            let temp = write_temp_file(&py_file, &script)?;
+            let runtime_deps = self
+                .run_python_mapped(Some(&source_map), None, &temp)
+                .inspect_err(|_| {
+                    let _ = std::fs::remove_file(&temp);
+                })?;
+            match self.config.delete_python() {
+                // the temporary file is deleted after the run, and so is the script of a previous one
+                true => {
+                    let _ = std::fs::remove_file(&py_file);
+                }
+                false => replace_if_changed(&temp, &py_file)?,
+            }
+            runtime_deps
+        };
+        deps.extend(runtime_deps.iter().cloned());
+        if let Some(key) = key {
+            self.write_cache(&key, &runtime_deps)?;
+        }
+        self.write_depfile(&source_map, &runtime_deps)?;
+        Ok(true)
     }
 }
 
+/// Returns the absolute path of `file` (with `/` separators), as passed to the Python script,
+/// which runs in its own working directory.
+pub(crate) fn absolute_path(file: impl AsRef<path::Path>) -> String {
+    let file = file.as_ref();
+    path::absolute(file)
+        .unwrap_or_else(|_| file.to_path_buf())
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
     use std::path::Path;
 
     #[test]
@@ -452,6 +1265,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apply_verilog_directives() {
+        let convert = Convert::default();
+        assert_eq!(
+            convert.apply_verilog_regex("`define WIDTH `W`"),
+            "`define WIDTH {W}"
+        );
+        assert_eq!(
+            convert.apply_verilog_regex("`ifdef FOO wire `BAR; `endif"),
+            "`ifdef FOO wire `BAR; `endif"
+        );
+        assert_eq!(
+            convert.apply_verilog_regex("assign a = `BAR + `n`;"),
+            "assign a = `BAR + {n};"
+        );
+        assert_eq!(
+            convert.apply_verilog_regex("`include \"`name`.vh\""),
+            "`include \"{name}.vh\""
+        );
+        assert_eq!(
+            convert.apply_verilog_regex("`define STR(x) `\"x`\" // `line`"),
+            "`define STR(x) `\"x`\" // {line}"
+        );
+        assert_eq!(
+            convert.apply_verilog_regex("w`i`_d = \\`MACRO\\` ` a * 2 `"),
+            "w{i}_d = `MACRO` { a * 2 }"
+        );
+        assert_eq!(
+            convert.apply_verilog_regex("$display('{`d['k']`}');"),
+            "$display(\\'{{{d['k']}}}\\');"
+        );
+    }
+
+    #[test]
+    fn test_custom_delimiters() {
+        let config = Config::with_delimiters("!".to_string(), "${", "}", false, false, 4).unwrap();
+        let convert = Convert::new(config, FileOptions::default(), None, None);
+        assert_eq!(
+            convert.apply_verilog_regex("`define W ${w} \\${x} {y}"),
+            "`define W {w} ${{x}} {{y}}"
+        );
+        let config = Config::with_delimiters("!".to_string(), "{{", "}}", false, false, 4).unwrap();
+        let convert = Convert::new(config, FileOptions::default(), None, None);
+        assert_eq!(convert.apply_verilog_regex("a{{ n + 1 }}"), "a{ n + 1 }");
+        assert!(Config::with_delimiters("!".to_string(), "!", "!", false, false, 4).is_err());
+        assert!(Config::with_delimiters("!".to_string(), "", "`", false, false, 4).is_err());
+        assert!(Config::with_delimiters("##".to_string(), "#", "#", false, false, 4).is_err());
+    }
+
     #[test]
     fn test_switch_line_type() {
         let mut line_type = LineType::default();
@@ -470,6 +1332,10 @@ mod tests {
         assert_eq!(line_type, LineType::None);
         convert.switch_line_type(&mut line_type, "// Verilog comment");
         assert_eq!(line_type, LineType::Verilog);
+        convert.switch_line_type(&mut line_type, "//!     <INCLUDE \"lib/a.pytv\">");
+        assert_eq!(line_type, LineType::Include("lib/a.pytv".to_string()));
+        convert.switch_line_type(&mut line_type, "//! <INCLUDED>");
+        assert_eq!(line_type, LineType::PythonInline);
     }
 
     #[test]
@@ -478,6 +1344,15 @@ mod tests {
         assert_eq!(paths.verilog_file, Path::new("examples/test.v"));
         assert_eq!(paths.python_script_file, Path::new("examples/test.v.py"));
         assert_eq!(paths.inst_file, Path::new("examples/test.v.inst"));
+        assert_eq!(paths.cache_file, Path::new("examples/test.v.cache"));
+    }
+
+    #[test]
+    fn test_output_paths_only_replace_extension() {
+        let paths = Convert::output_paths(Path::new("pytv/top.pytv"), None);
+        assert_eq!(paths.verilog_file, Path::new("pytv/top.v"));
+        let paths = Convert::output_paths(Path::new("rtl.pytv/a.b.pytv"), None);
+        assert_eq!(paths.verilog_file, Path::new("rtl.pytv/a.b.v"));
     }
 
     #[test]
@@ -501,20 +1376,451 @@ mod tests {
 
     #[test]
     fn test_render_python_script_contains_output_print() {
-        let mut input = std::env::temp_dir();
-        input.push(format!("pytv_render_{}.pytv", std::process::id()));
-        std::fs::write(&input, "wire `1+2`;\n").unwrap();
+        let (convert, _dir) = temp_convert("render", "wire `1+2`;\n");
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains("print(f'wire {1+2};')"));
+    }
+
+    #[test]
+    fn test_source_map_rewrites_traceback() {
+        let (convert, _dir) = temp_convert(
+            "source_map",
+            "//! for i in range(2):\nwire w`i`;\nwire x`1/0`;\n",
+        );
+        let input_str = convert.file_options.input.clone();
+        let mut script = Vec::new();
+        let source_map = convert.convert_mapped(&mut script).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let print_line = script
+            .lines()
+            .position(|line| line.contains("print(f'wire x{1/0};')"))
+            .unwrap()
+            + 1;
+        let loc = source_map.lookup(print_line).unwrap();
+        assert_eq!(loc.file, input_str);
+        assert_eq!(loc.line, 3);
+        assert_eq!(loc.text, "wire x`1/0`;");
+        let traceback = format!(
+            concat!(
+                "Traceback (most recent call last):\n",
+                "  File \"out.v.py\", line {}, in <module>\n",
+                "    print(f'wire x{{1/0}};')\n",
+                "                   ~^~\n",
+                "ZeroDivisionError: division by zero\n",
+            ),
+            print_line
+        );
+        assert_eq!(
+            source_map.rewrite_traceback("out.v.py", &traceback),
+            format!(
+                concat!(
+                    "Traceback (most recent call last):\n",
+                    "  File \"{}\", line 3, in <module>\n",
+                    "    wire x`1/0`;\n",
+                    "ZeroDivisionError: division by zero\n",
+                ),
+                input_str
+            )
+        );
+    }
+
+    /// Returns the conversion of `source`, written to a template in the temporary directory `name`,
+    /// which lives as long as the returned directory.
+    fn temp_convert(name: &str, source: &str) -> (Convert, TempDir) {
+        let dir = TempDir::new(name);
+        let input = dir.write("top.pytv", source);
         let convert = Convert::new(
             Config::default(),
             FileOptions {
-                input: input.to_string_lossy().to_string(),
-                output: None,
+                input,
+                ..Default::default()
+            },
+            None,
+            None,
+        );
+        (convert, dir)
+    }
+
+    #[test]
+    fn test_indentation_error_span() {
+        let (convert, _dir) = temp_convert("indent_err", "//!   a = 1\n//! b = 2\n");
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Indentation { .. }));
+        assert_eq!(err.span().line, 2);
+        assert_eq!(err.span().column, 5);
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    #[cfg(feature = "inst")]
+    #[test]
+    fn test_inst_error_spans() {
+        let (convert, _dir) =
+            temp_convert("inst_err", "wire a;\n//! <INST>\n//! <INST>\n//! </INST>\n");
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Inst { .. }));
+        assert_eq!((err.span().line, err.span().column), (3, 5));
+
+        let (convert, _dir) = temp_convert(
+            "yaml_err",
+            "//! <INST>\n//! module: m\n//! name: [x\n//! </INST>\n",
+        );
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Yaml { .. }));
+        assert_eq!(err.span().file, convert.file_options.input);
+        assert!(err.span().line > 1);
+        assert_eq!(err.exit_code(), 6);
+    }
+
+    #[test]
+    fn test_include_indentation_and_cycle() {
+        let dir = TempDir::new("include");
+        let top = dir.write(
+            "top.pytv",
+            "//! for i in range(2):\n//!     <INCLUDE lib/body.pytv>\n//! #\nend\n",
+        );
+        dir.write("lib/body.pytv", "//! j = i\nwire w`j`;\n");
+        let convert = Convert::new(
+            Config::default(),
+            FileOptions {
+                input: top.clone(),
+                ..Default::default()
             },
             None,
             None,
         );
         let script = convert.render_python_script().unwrap();
-        assert!(script.contains("print(f'wire {1+2};')"));
-        let _ = std::fs::remove_file(input);
+        assert!(script
+            .contains("for i in range(2):\n    j = i\n    print(f'wire w{j};')\n#\nprint(f'end')"));
+
+        dir.write("lib/body.pytv", "//! <INCLUDE ../top.pytv>\n");
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Include { .. }));
+        assert!(err.to_string().contains("top.pytv -> "));
+        assert_eq!(err.span().line, 1);
+    }
+
+    #[test]
+    fn test_explicit_scope_end() {
+        let (convert, _dir) = temp_convert(
+            "scope_end",
+            concat!(
+                "//! for i in range(2):\n",
+                "//!     if i:\n",
+                "a\n",
+                "//!     else:\n",
+                "b\n",
+                "//!     endif\n",
+                "c\n",
+                "//! endfor\n",
+                "d\n",
+            ),
+        );
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains(concat!(
+            "for i in range(2):\n",
+            "    if i:\n",
+            "        print(f'a')\n",
+            "    else:\n",
+            "        print(f'b')\n",
+            "    print(f'c')\n",
+            "print(f'd')\n",
+        )));
+
+        let (convert, _dir) = temp_convert(
+            "scope_mismatch",
+            "//! for i in range(2):\n//!     if i:\na\n//! endfor\n",
+        );
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Scope { .. }));
+        assert_eq!(err.span().line, 4);
+        assert!(err.to_string().contains("opened at line 2"));
+
+        let (convert, _dir) = temp_convert(
+            "scope_unended",
+            "//! for i in range(2):\n//!     if i:\na\n//!     endif\n",
+        );
+        let err = convert.render_python_script().unwrap_err();
+        assert_eq!((err.span().line, err.exit_code()), (1, 9));
+    }
+
+    #[test]
+    fn test_python_logical_lines() {
+        let (convert, _dir) = temp_convert(
+            "logical_lines",
+            concat!(
+                "//! d = {\n",
+                "//!     'a':\n",
+                "//!         1}\n",
+                "//! s = 'x:' + f\"{d['a']:>4}\" # y:\n",
+                "//! f = lambda x: x[1:]\n",
+                "a\n",
+                "//! if d and \\\n",
+                "//!         s:\n",
+                "b\n",
+                "/*!\n",
+                "t = \"\"\"\n",
+                "for:\n",
+                "\"\"\"\n",
+                "*/\n",
+                "c\n",
+            ),
+        );
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains("lambda x: x[1:]\nprint(f'a')\n"));
+        assert!(script.contains("        s:\n    print(f'b')\n"));
+        assert!(script.contains("    \"\"\"\n    print(f'c')\n"));
+
+        let (convert, _dir) = temp_convert("open_statement", "//! x = [\nwire a;\n//! ]\n");
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Syntax { .. }));
+        assert_eq!((err.span().line, err.exit_code()), (2, 10));
+        assert!(err.to_string().contains("started at line 1"));
+    }
+
+    #[test]
+    fn test_python_block_relative_indent() {
+        let (convert, _dir) = temp_convert(
+            "block_indent",
+            concat!(
+                "//! for i in range(2):\n",
+                "/*!\n",
+                "  if i:\n",
+                "      x = i\n",
+                "\n",
+                "  else:\n",
+                "      x = 0\n",
+                "  y = x\n",
+                "*/\n",
+                "wire w`y`;\n",
+                "//! endfor\n",
+            ),
+        );
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains(concat!(
+            "for i in range(2):\n",
+            "    if i:\n",
+            "        x = i\n",
+            "\n",
+            "    else:\n",
+            "        x = 0\n",
+            "    y = x\n",
+            "    print(f'wire w{y};')\n",
+        )));
+    }
+
+    #[test]
+    fn test_single_line_python_blocks() {
+        let (convert, _dir) = temp_convert(
+            "block_lines",
+            concat!(
+                "/*! x = 1 */\n",
+                "/*! import math\n",
+                "y = math.floor(x)\n",
+                "z = y */\n",
+                "//! for i in range(2):\n",
+                "    /*! for j in range(2):\n",
+                "        w = i * j */\n",
+                "wire w`w`;\n",
+            ),
+        );
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains(concat!(
+            "x = 1\n",
+            "import math\n",
+            "y = math.floor(x)\n",
+            "z = y\n",
+            "for i in range(2):\n",
+            "    for j in range(2):\n",
+            "        w = i * j\n",
+            "        print(f'wire w{w};')\n",
+        )));
+
+        // a `*/` in a comment or string does not close the block
+        let (convert, _dir) = temp_convert(
+            "block_comment_end",
+            "/*!\nx = 1  # see a */\ns = 'a */'\n*/\nwire w;\n",
+        );
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains("x = 1  # see a */\ns = 'a */'\nprint(f'wire w;')"));
+        assert!(!script.contains("print(f'*/')"));
+
+        let (convert, _dir) = temp_convert("block_unterminated", "a\n/*!\nx = 1\n");
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Syntax { .. }));
+        assert_eq!(err.span().line, 2);
+    }
+
+    #[cfg(feature = "inst")]
+    #[test]
+    fn test_unclosed_constructs_at_end() {
+        let (convert, _dir) = temp_convert(
+            "unclosed",
+            concat!(
+                "//! for i in range(2):\n",
+                "//!     if i:\n",
+                "a\n",
+                "//!     endif\n",
+                "//!     <INST>\n",
+                "//!     module: m\n",
+                "/*!\n",
+                "x = 1\n",
+            ),
+        );
+        let err = convert.render_python_script().unwrap_err();
+        assert!(matches!(err, Error::Syntax { .. }));
+        assert_eq!(err.span().line, 1);
+        let message = err.to_string();
+        assert!(message.contains("`for` scope opened at line 1 is not ended."));
+        assert!(message.contains("<INST> opened at line 5 is not closed"));
+        assert!(message.contains("Python block opened at line 7 is not closed"));
+    }
+
+    #[test]
+    fn test_convert_from_reader() {
+        let source: &[u8] = b"//!   x = 1\nwire w`x`;\n//! y = 2\n";
+        let convert = Convert::default()
+            .with_reader("virtual/top.pytv", source)
+            .unwrap();
+        assert_eq!(
+            convert.output_paths_from_options().verilog_file,
+            Path::new("virtual/top.v")
+        );
+        let err = convert.render_python_script().unwrap_err();
+        assert_eq!(err.span().file, "virtual/top.pytv");
+        assert_eq!(err.span().line, 3);
+        let convert =
+            convert.with_source("virtual/top.pytv", "//! for i in range(2):\nwire w`i`;\n");
+        let script = convert.render_python_script().unwrap();
+        assert!(script.contains("for i in range(2):\n    print(f'wire w{i};')"));
+
+        // the input read from stdin has placeholder outputs, without `.inst` file on stdout
+        let convert = Convert::builder()
+            .run_mode(crate::RunMode::RunAndDelete)
+            .stdout(true)
+            .build()
+            .unwrap()
+            .with_source(STDIN_FILE, "wire w;\n");
+        let outputs = convert.outputs();
+        assert_eq!(outputs.verilog_file, Path::new("stdin.v"));
+        assert_eq!(convert.output_files(), [outputs.verilog_file]);
+    }
+
+    #[test]
+    fn test_builder_run_mode() {
+        use crate::RunMode;
+        let config = Config::new(
+            "!".to_string(),
+            Config::default_template_re(),
+            false,
+            true,
+            4,
+        );
+        assert_eq!(config.run_mode(), RunMode::Convert);
+        assert!(!config.delete_python());
+        let convert = Convert::builder()
+            .input("top.pytv")
+            .run_mode(RunMode::RunAndDelete)
+            .include_path("lib")
+            .preamble("a.py")
+            .preamble("b.py")
+            .build()
+            .unwrap();
+        assert_eq!(convert.config.run_mode(), RunMode::RunAndDelete);
+        assert_eq!(convert.config.include_paths, vec!["lib".to_string()]);
+        assert_eq!(convert.preambles.len(), 2);
+        assert!(Convert::builder().delimiters("!", "!").build().is_err());
+    }
+
+    #[test]
+    fn test_python_environment_and_working_dir() {
+        use crate::RunMode;
+        let dir = TempDir::new("python_env");
+        dir.write("lib/chip_cfg.py", "WIDTH = 8\n");
+        dir.write("rtl/depth.txt", "16\n");
+        let input = dir.join("rtl/top.pytv").to_string_lossy().to_string();
+        let source = concat!(
+            "//! import chip_cfg, os\n",
+            "//! depth = open('depth.txt').read().strip()\n",
+            "//! chip = os.environ['CHIP']\n",
+            "// `chip_cfg.WIDTH` `depth` `chip`\n",
+        );
+        std::fs::write(&input, source).unwrap();
+        let convert = Convert::builder()
+            .input(&input)
+            .run_mode(RunMode::RunAndDelete)
+            .python_path(dir.join("lib").to_string_lossy())
+            .python_env("CHIP", "a100")
+            .build()
+            .unwrap();
+        assert_eq!(convert.python_cwd(), dir.join("rtl"));
+        convert.convert_to_file().unwrap();
+        let verilog = std::fs::read_to_string(convert.outputs().verilog_file).unwrap();
+        assert_eq!(verilog, "// 8 16 a100\n");
+    }
+
+    #[test]
+    fn test_failed_run_keeps_or_deletes_outputs() {
+        use crate::{OnFailure, RunMode};
+        let dir = TempDir::new("atomic");
+        let input = dir.join("top.pytv").to_string_lossy().to_string();
+        let convert = |on_failure| {
+            Convert::builder()
+                .input(&input)
+                .run_mode(RunMode::Run)
+                .on_failure(on_failure)
+                .build()
+                .unwrap()
+        };
+        std::fs::write(&input, "wire a;\n").unwrap();
+        convert(OnFailure::Keep).convert_to_file().unwrap();
+        let outputs = convert(OnFailure::Keep).outputs();
+        std::fs::write(&input, "wire b;\n//! raise ValueError('bad')\n").unwrap();
+        let err = convert(OnFailure::Keep).convert_to_file().unwrap_err();
+        assert!(matches!(err, Error::Python { .. }));
+        let verilog = std::fs::read_to_string(&outputs.verilog_file).unwrap();
+        assert_eq!(verilog, "wire a;\n");
+        assert!(outputs.inst_file.exists());
+        let script = std::fs::read_to_string(&outputs.python_script_file).unwrap();
+        assert!(script.contains("wire a;") && !script.contains("wire b;"));
+        let leftovers = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "tmp");
+        assert_eq!(leftovers.count(), 0);
+        convert(OnFailure::Delete).convert_to_file().unwrap_err();
+        assert!(!outputs.verilog_file.exists());
+        assert!(!outputs.inst_file.exists());
+        assert!(!outputs.python_script_file.exists());
+    }
+
+    #[test]
+    fn test_python_stdin_and_render_verilog() {
+        use crate::RunMode;
+        let dir = TempDir::new("stdin");
+        let input = dir.join("top.pytv").to_string_lossy().to_string();
+        let convert = |run_mode| {
+            Convert::builder()
+                .input(&input)
+                .run_mode(run_mode)
+                .python_stdin(true)
+                .build()
+                .unwrap()
+        };
+        std::fs::write(&input, "//! n = 2\nwire [`n`:0] a;\n").unwrap();
+        let verilog = convert(RunMode::Convert).render_verilog().unwrap();
+        assert_eq!(verilog, "wire [2:0] a;\n");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        convert(RunMode::RunAndDelete).convert_to_file().unwrap();
+        let outputs = convert(RunMode::RunAndDelete).outputs();
+        let verilog = std::fs::read_to_string(&outputs.verilog_file).unwrap();
+        assert_eq!(verilog, "wire [2:0] a;\n");
+        assert!(!outputs.python_script_file.exists());
+        std::fs::write(&input, "wire a;\n//! raise ValueError('bad')\n").unwrap();
+        let err = convert(RunMode::Convert).render_verilog().unwrap_err();
+        match err {
+            Error::Python { span, .. } => {
+                assert_eq!((span.file.as_str(), span.line), (&input[..], 2))
+            }
+            err => panic!("unexpected error: {err}"),
+        }
     }
 }
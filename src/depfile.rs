@@ -0,0 +1,192 @@
+use super::Convert;
+use crate::cache::write_if_changed;
+use crate::convert::STDIN_FILE;
+use crate::error::Result;
+use crate::source_map::{MappedWriter, SourceMap, UTILITY_FILE};
+use std::io::{Result as IoResult, Write};
+use std::path::Path;
+
+/// The environment variable naming the file the generated script writes the files it read to.
+pub(crate) const DEPS_FILE_ENV: &str = "PYTV_DEPS_FILE";
+
+/// Python code recording the files opened for reading and the files of the imported modules
+/// (except the standard library and installed packages), written at exit
+/// to the file named by `PYTV_DEPS_FILE` (if it is set).
+const DEPENDENCY_HOOK: &str = r#"# PyTV dependency tracking:
+import atexit as _atexit, builtins as _builtins, io as _io, os as _os, sys as _sys, sysconfig as _sysconfig
+_pytv_deps = set()
+_pytv_open = _builtins.open
+def _pytv_tracked_open(file, mode='r', *args, **kwargs):
+    if isinstance(file, (str, bytes, _os.PathLike)) and not set(mode) & set('wax+'):
+        _pytv_deps.add(_os.path.abspath(_os.fsdecode(file)))
+    return _pytv_open(file, mode, *args, **kwargs)
+_builtins.open = _io.open = _pytv_tracked_open
+def _pytv_module_files():
+    libraries = {_sysconfig.get_path(name) for name in ('stdlib', 'platstdlib', 'purelib', 'platlib')}
+    libraries.update(p for p in _sys.path if _os.path.basename(p) in ('site-packages', 'dist-packages'))
+    libraries = tuple(_os.path.join(_os.path.abspath(p), '') for p in libraries if p)
+    for name, module in list(_sys.modules.items()):
+        file = getattr(module, '__file__', None) if name != '__main__' else None
+        if isinstance(file, str) and _os.path.isfile(file):
+            file = _os.path.abspath(file)
+            if not file.startswith(libraries):
+                yield file
+def _pytv_write_deps():
+    if _os.environ.get('PYTV_DEPS_FILE'):
+        deps = _pytv_deps.union(_pytv_module_files())
+        with _pytv_open(_os.environ['PYTV_DEPS_FILE'], 'w') as f:
+            f.write(''.join(dep + '\n' for dep in sorted(deps)))
+_atexit.register(_pytv_write_deps)
+"#;
+
+impl Convert {
+    /// Returns `true` if the generated script records the files it reads and imports,
+    /// which is needed for the depfile and the cache.
+    pub(crate) fn tracks_dependencies(&self) -> bool {
+        self.config.run_python() && (self.file_options.depfile.is_some() || self.file_options.cache)
+    }
+
+    /// Writes the dependency tracking hook, if it is needed.
+    pub(crate) fn write_dependency_hook<W: Write>(
+        &self,
+        stream: &mut MappedWriter<W>,
+    ) -> IoResult<()> {
+        if self.tracks_dependencies() {
+            stream.write_section(UTILITY_FILE, DEPENDENCY_HOOK)?;
+            writeln!(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the file the generated script writes the files it read to.
+    pub(crate) fn runtime_deps_file(&self) -> String {
+        format!("{}.deps", self.outputs().verilog_file.to_string_lossy())
+    }
+
+    /// Reads and removes the files recorded by the dependency tracking hook.
+    ///
+    /// Paths below the current directory are made relative to it,
+    /// and the output files are left out.
+    pub(crate) fn take_runtime_deps(&self) -> Vec<String> {
+        let deps_file = self.runtime_deps_file();
+        let Ok(deps) = std::fs::read_to_string(&deps_file) else {
+            return Vec::new();
+        };
+        let _ = std::fs::remove_file(&deps_file);
+        let outputs = self.output_files();
+        deps.lines()
+            .map(relative_to_cwd)
+            .filter(|dep| !outputs.iter().any(|output| Path::new(dep) == output))
+            .collect()
+    }
+
+    /// Returns all files the outputs depend on: the template, the included templates,
+    /// the preambles and the files read or imported by the Python script (`runtime_deps`).
+    pub fn dependencies(&self, source_map: &SourceMap, runtime_deps: &[String]) -> Vec<String> {
+        let mut deps: Vec<String> = source_map
+            .inputs()
+            .iter()
+            .filter(|input| *input != STDIN_FILE)
+            .cloned()
+            .collect();
+        for dep in runtime_deps {
+            if !deps.contains(dep) {
+                deps.push(dep.clone());
+            }
+        }
+        deps
+    }
+
+    /// Writes the depfile, if it is requested, listing the output files and their dependencies.
+    pub(crate) fn write_depfile(
+        &self,
+        source_map: &SourceMap,
+        runtime_deps: &[String],
+    ) -> Result<()> {
+        let Some(depfile) = &self.file_options.depfile else {
+            return Ok(());
+        };
+        let targets = self
+            .output_files()
+            .iter()
+            .map(|file| escape_make_path(&file.to_string_lossy()))
+            .collect::<Vec<_>>();
+        let mut contents = format!("{}:", targets.join(" "));
+        for dep in self.dependencies(source_map, runtime_deps) {
+            contents.push_str(" \\\n  ");
+            contents.push_str(&escape_make_path(&dep));
+        }
+        contents.push('\n');
+        write_if_changed(depfile, contents.as_bytes())
+    }
+}
+
+/// Returns `path` relative to the current directory if it is below it.
+pub(crate) fn relative_to_cwd(path: &str) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let path = Path::new(path);
+    path.strip_prefix(&cwd)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Escapes a path for a Make rule, as GCC does in depfiles.
+fn escape_make_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.replace('\\', "/").chars() {
+        match c {
+            ' ' | '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_depfile_tracks_runtime_reads() {
+        use crate::RunMode;
+        let dir = TempDir::new("depfile");
+        let csv = dir.join("coef.csv");
+        std::fs::write(&csv, "3\n").unwrap();
+        let input = dir.join("top.pytv").to_string_lossy().to_string();
+        let source = format!(
+            "//! import json, coefs\n//! n = int(open({:?}).read())\nwire [`n`:0] w;\n",
+            csv.to_string_lossy()
+        );
+        std::fs::write(&input, source).unwrap();
+        let module = dir.write("lib/coefs.py", "");
+        let depfile = dir.join("top.d");
+        let convert = Convert::builder()
+            .input(&input)
+            .run_mode(RunMode::RunAndDelete)
+            .python_path(dir.join("lib").to_string_lossy())
+            .depfile(depfile.to_string_lossy())
+            .build()
+            .unwrap();
+        assert!(convert.update_files().unwrap());
+        let outputs = convert.outputs();
+        let deps = std::fs::read_to_string(&depfile).unwrap();
+        let (targets, deps) = deps.split_once(':').unwrap();
+        assert_eq!(
+            targets,
+            format!(
+                "{} {}",
+                outputs.verilog_file.display(),
+                outputs.inst_file.display()
+            )
+        );
+        assert!(deps.contains(&input));
+        assert!(deps.contains(&*csv.to_string_lossy()));
+        assert!(deps.contains(&module));
+        assert!(!deps.contains("json"));
+        assert!(!Path::new(&convert.runtime_deps_file()).exists());
+    }
+}
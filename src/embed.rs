@@ -0,0 +1,102 @@
+use crate::worker::{Job, JobOutput, RUNNER_SCRIPT};
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+use pyo3::types::{PyBytes, PyModule};
+use std::ffi::CString;
+
+/// The `_run` function of [`RUNNER_SCRIPT`], loaded into the embedded interpreter once.
+static RUNNER: GILOnceCell<Py<PyAny>> = GILOnceCell::new();
+
+/// Runs a job in the Python interpreter embedded in PyTV,
+/// as a worker would (see [`PythonBackend::Embedded`](crate::PythonBackend)).
+///
+/// The script runs in the working directory and with the environment variables of the job,
+/// which are set for the whole process while it runs.
+/// If the runner itself fails, the Python exception is returned as the error output.
+pub(crate) fn run(job: &Job) -> JobOutput {
+    Python::with_gil(|py| {
+        run_with_gil(py, job).unwrap_or_else(|err| JobOutput {
+            exit_code: Some(1),
+            stdout: Vec::new(),
+            inst: Vec::new(),
+            stderr: format!("{err}\n").into_bytes(),
+        })
+    })
+}
+
+fn run_with_gil(py: Python, job: &Job) -> PyResult<JobOutput> {
+    let runner = RUNNER.get_or_try_init(py, || -> PyResult<_> {
+        let code = CString::new(RUNNER_SCRIPT)?;
+        let module = PyModule::from_code(py, &code, c"<pytv-runner>", c"_pytv_runner")?;
+        Ok(module.getattr("_run")?.unbind())
+    })?;
+    let env = job
+        .env
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"));
+    let (exit_code, stdout, inst, stderr): (i32, String, String, String) = runner
+        .bind(py)
+        .call1((
+            PyBytes::new(py, job.script),
+            job.file_name,
+            &job.cwd,
+            &job.python_path,
+            env.collect::<Vec<_>>(),
+        ))?
+        .extract()?;
+    Ok(JobOutput {
+        exit_code: Some(exit_code),
+        stdout: stdout.into_bytes(),
+        inst: inst.into_bytes(),
+        stderr: stderr.into_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use crate::{Convert, Error, PythonBackend, RunMode};
+
+    #[test]
+    fn test_embedded_python_backend() {
+        let dir = TempDir::new("embed");
+        let input = dir.join("top.pytv").to_string_lossy().to_string();
+        let convert = || {
+            Convert::builder()
+                .input(&input)
+                .python_backend(PythonBackend::Embedded)
+                .build()
+                .unwrap()
+        };
+        std::fs::write(
+            &input,
+            "//! import os\n//! _inst_file.write('u0\\n')\n//! d = os.path.basename(os.getcwd())\nwire `d`;\n",
+        )
+        .unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let verilog = convert().render_verilog().unwrap();
+        let name = dir.file_name().unwrap().to_string_lossy();
+        assert_eq!(verilog, format!("wire {name};\n"));
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let convert_to_file = Convert::builder()
+            .input(&input)
+            .run_mode(RunMode::RunAndDelete)
+            .python_backend(PythonBackend::Embedded)
+            .build()
+            .unwrap();
+        convert_to_file.convert_to_file().unwrap();
+        let inst = std::fs::read_to_string(convert_to_file.outputs().inst_file).unwrap();
+        assert_eq!(inst, "u0\n");
+        std::fs::write(&input, "wire a;\n//! x = 1\n//! raise KeyError(x)\n").unwrap();
+        match convert().render_verilog().unwrap_err() {
+            Error::Python {
+                span, traceback, ..
+            } => {
+                assert_eq!((span.file.as_str(), span.line), (&input[..], 3));
+                assert!(traceback.contains("KeyError: 1"));
+            }
+            err => panic!("unexpected error: {err}"),
+        }
+    }
+}
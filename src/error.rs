@@ -0,0 +1,187 @@
+use crate::limits::Limit;
+use std::fmt;
+
+/// Location of an error in an input file.
+///
+/// Lines and columns are 1-based; `0` means the position is unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    /// The input file.
+    pub file: String,
+    /// The line number in the input file.
+    pub line: usize,
+    /// The column number in the input line.
+    pub column: usize,
+}
+
+impl Span {
+    /// Creates a new `Span` with the given file, line and column.
+    pub fn new(file: &str, line: usize, column: usize) -> Span {
+        Span {
+            file: file.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if self.line > 0 {
+            write!(f, ":{}", self.line)?;
+            if self.column > 0 {
+                write!(f, ":{}", self.column)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Errors reported by PyTV.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Python code is not indented consistently.
+    Indentation { span: Span, message: String },
+    /// Instantiation block (`<INST>` ... `</INST>`) is malformed.
+    Inst { span: Span, message: String },
+    /// Instantiation block contents are not valid YAML.
+    #[cfg(feature = "inst")]
+    Yaml {
+        span: Span,
+        source: serde_yaml::Error,
+    },
+    /// Included template (`<INCLUDE path>`) cannot be found or is included recursively.
+    Include { span: Span, message: String },
+    /// Inline Python scopes are not balanced (`end`, `endfor`, `endif`, ...).
+    Scope { span: Span, message: String },
+    /// Template syntax is malformed (e.g. an unterminated Python block or statement).
+    Syntax { span: Span, message: String },
+    /// Configuration is invalid.
+    Config { span: Span, message: String },
+    /// Reading an input or writing an output failed.
+    Io { span: Span, source: std::io::Error },
+    /// The generated Python script failed.
+    Python {
+        span: Span,
+        exit_code: Option<i32>,
+        traceback: String,
+    },
+    /// The generated Python script exceeded a resource limit and was killed
+    /// (the span is the template).
+    Limit { span: Span, limit: Limit },
+}
+
+/// Result type used by PyTV.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Creates an I/O error concerning the given file.
+    pub(crate) fn io(file: &str, source: std::io::Error) -> Error {
+        Error::Io {
+            span: Span::new(file, 0, 0),
+            source,
+        }
+    }
+
+    /// Returns the location of the error.
+    pub fn span(&self) -> &Span {
+        match self {
+            Error::Indentation { span, .. } => span,
+            Error::Inst { span, .. } => span,
+            Error::Include { span, .. } => span,
+            Error::Config { span, .. } => span,
+            Error::Scope { span, .. } => span,
+            Error::Syntax { span, .. } => span,
+            #[cfg(feature = "inst")]
+            Error::Yaml { span, .. } => span,
+            Error::Io { span, .. } => span,
+            Error::Python { span, .. } => span,
+            Error::Limit { span, .. } => span,
+        }
+    }
+
+    /// Returns the process exit code for the error category.
+    ///
+    /// | Category    | Code |
+    /// |-------------|------|
+    /// | Config      | 2    |
+    /// | I/O         | 3    |
+    /// | Indentation | 4    |
+    /// | INST        | 5    |
+    /// | YAML        | 6    |
+    /// | Python      | 7    |
+    /// | INCLUDE     | 8    |
+    /// | Scope       | 9    |
+    /// | Syntax      | 10   |
+    /// | Limit       | 11   |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config { .. } => 2,
+            Error::Io { .. } => 3,
+            Error::Indentation { .. } => 4,
+            Error::Inst { .. } => 5,
+            #[cfg(feature = "inst")]
+            Error::Yaml { .. } => 6,
+            Error::Python { .. } => 7,
+            Error::Include { .. } => 8,
+            Error::Scope { .. } => 9,
+            Error::Syntax { .. } => 10,
+            Error::Limit { .. } => 11,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.span().file.is_empty() {
+            write!(f, "{}: ", self.span())?;
+        }
+        match self {
+            Error::Indentation { message, .. } => write!(f, "{}", message),
+            Error::Inst { message, .. } => write!(f, "{}", message),
+            Error::Include { message, .. } => write!(f, "{}", message),
+            Error::Config { message, .. } => write!(f, "{}", message),
+            Error::Scope { message, .. } => write!(f, "{}", message),
+            Error::Syntax { message, .. } => write!(f, "{}", message),
+            #[cfg(feature = "inst")]
+            Error::Yaml { source, .. } => write!(f, "Invalid YAML in <INST>: {}", source),
+            Error::Io { source, .. } => write!(f, "{}", source),
+            Error::Python {
+                exit_code,
+                traceback,
+                ..
+            } => write!(
+                f,
+                "Python script failed with exit code: {}\n{}",
+                exit_code.unwrap_or(-1),
+                traceback
+            ),
+            Error::Limit { limit, .. } => {
+                write!(f, "Python script killed after exceeding the {}.", limit)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    /// Converts an I/O error not attributed to a specific file.
+    fn from(source: std::io::Error) -> Self {
+        Error::Io {
+            span: Span::default(),
+            source,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "inst")]
+            Error::Yaml { source, .. } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
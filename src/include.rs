@@ -0,0 +1,83 @@
+use super::Convert;
+use crate::error::{Error, Result, Span};
+use std::path::{Path, PathBuf};
+
+impl Convert {
+    /// Parses the path of an include directive (`<INCLUDE path>`) in an inline Python line.
+    pub(crate) fn include_path(python: &str) -> Option<String> {
+        let path = python.trim().strip_prefix("<INCLUDE")?.strip_suffix('>')?;
+        if !path.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let path = path.trim();
+        let path = path
+            .strip_prefix('"')
+            .and_then(|p| p.strip_suffix('"'))
+            .unwrap_or(path);
+        (!path.is_empty()).then(|| path.to_string())
+    }
+
+    /// Returns the key identifying a file in the include chain.
+    pub(crate) fn include_key(path: &Path) -> PathBuf {
+        path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    /// Resolves an included file relative to the including file, then to the include paths.
+    fn resolve_include(&self, includer: &str, include: &str) -> Option<PathBuf> {
+        let include = Path::new(include);
+        if include.is_absolute() {
+            return include.is_file().then(|| include.to_path_buf());
+        }
+        let includer_dir = Path::new(includer)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        std::iter::once(includer_dir)
+            .chain(self.config.include_paths.iter().map(PathBuf::from))
+            .map(|dir| dir.join(include))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Resolves and reads an included file, and pushes it onto the include chain.
+    ///
+    /// Returns the path of the included file (for diagnostics) and its contents.
+    pub(crate) fn open_include(
+        &self,
+        include: &str,
+        include_chain: &mut Vec<PathBuf>,
+        span: &Span,
+    ) -> Result<(String, String)> {
+        let path = self
+            .resolve_include(&span.file, include)
+            .ok_or_else(|| Error::Include {
+                span: span.clone(),
+                message: format!(
+                    "Cannot find included file `{}` (relative to `{}`{}).",
+                    include,
+                    span.file,
+                    self.config
+                        .include_paths
+                        .iter()
+                        .map(|dir| format!(", `{}`", dir))
+                        .collect::<String>()
+                ),
+            })?;
+        let key = Self::include_key(&path);
+        if include_chain.contains(&key) {
+            let chain = include_chain
+                .iter()
+                .chain(std::iter::once(&key))
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(Error::Include {
+                span: span.clone(),
+                message: format!("Include cycle detected: {}", chain),
+            });
+        }
+        let file = path.to_string_lossy().replace("\\", "/");
+        let source = std::fs::read_to_string(&path).map_err(|err| Error::io(&file, err))?;
+        include_chain.push(key);
+        Ok((file, source))
+    }
+}
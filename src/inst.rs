@@ -1,6 +1,6 @@
 use super::Convert;
+use crate::error::{Error, Result, Span};
 use regex::{self, Regex};
-use std::error::Error;
 use std::io::Write;
 
 /// Represents the state of module instantiation.
@@ -10,6 +10,21 @@ enum InstState {
     End,
 }
 
+/// Represents the instantiation block being parsed.
+#[derive(Debug, Default)]
+pub(crate) struct InstBlock {
+    /// Whether the parser is between `<INST>` and `</INST>`.
+    pub(crate) within: bool,
+    /// The YAML contents collected so far.
+    body: String,
+    /// The Python indentation of the `<INST>` line.
+    indent_space: usize,
+    /// The location of the `<INST>` line.
+    pub(crate) open_span: Span,
+    /// The column at which the YAML contents start.
+    body_column: usize,
+}
+
 fn yaml_value_as_str(value: &serde_yaml::Value) -> Option<String> {
     match value {
         serde_yaml::Value::String(s) => Some(s.clone()),
@@ -20,51 +35,75 @@ fn yaml_value_as_str(value: &serde_yaml::Value) -> Option<String> {
 }
 
 impl Convert {
+    /// Processes a Python line, collecting and expanding instantiation blocks.
+    ///
+    /// `span` is the location where `line` starts in the input,
+    /// and all generated lines are indented by `base_indent` spaces.
     pub(crate) fn process_python_line<W: Write>(
         &self,
         line: &str,
         py_indent_prior: usize,
+        base_indent: usize,
         stream: &mut W,
-        within_inst: &mut bool,
-        inst_str: &mut String,
-        inst_indent_space: &mut usize,
-    ) -> Result<(), Box<dyn Error>> {
+        inst: &mut InstBlock,
+        span: Span,
+    ) -> Result<()> {
+        let io_err = |err| Error::io(&span.file, err);
         match self.inst_state(line) {
             InstState::Begin => {
                 // calculate the space before the <INST>
                 // and print the Python code before the <INST>
-                let all_space = &line.len() - line.trim_start().len();
+                let all_space = line.len() - line.trim_start().len();
+                let inst_span = Span {
+                    column: span.column + all_space,
+                    ..span.clone()
+                };
                 if all_space < py_indent_prior {
-                    return Err("Indentation error: <INST> is not properly indented.".into());
+                    return Err(Error::Indentation {
+                        span: inst_span,
+                        message: "<INST> is not properly indented.".to_string(),
+                    });
                 }
-                *inst_indent_space = all_space - py_indent_prior;
-                if *within_inst {
-                    return Err("Nested <INST> is not allowed.".into());
+                if inst.within {
+                    return Err(Error::Inst {
+                        span: inst_span,
+                        message: "Nested <INST> is not allowed.".to_string(),
+                    });
                 }
-                *within_inst = true;
-                writeln!(stream, "{}print('// INST')", " ".repeat(*inst_indent_space))?;
+                inst.within = true;
+                inst.indent_space = base_indent + all_space - py_indent_prior;
+                inst.body_column = span.column + py_indent_prior;
+                inst.open_span = inst_span;
+                writeln!(stream, "{}print('// INST')", " ".repeat(inst.indent_space))
+                    .map_err(io_err)?;
             }
             InstState::End => {
-                if !*within_inst {
-                    return Err("Encountering </INST> with no <INST> to end.".into());
+                if !inst.within {
+                    return Err(Error::Inst {
+                        span,
+                        message: "Encountering </INST> with no <INST> to end.".to_string(),
+                    });
                 }
-                *within_inst = false;
-                self.print_inst(stream, inst_str, *inst_indent_space)?;
-                inst_str.clear();
+                inst.within = false;
+                self.print_inst(stream, inst)?;
+                inst.body.clear();
                 writeln!(
                     stream,
                     "{}print('// END of INST')",
-                    " ".repeat(*inst_indent_space)
-                )?;
-                *inst_indent_space = 0;
+                    " ".repeat(inst.indent_space)
+                )
+                .map_err(io_err)?;
+                inst.indent_space = 0;
             }
             _ => {
-                let useful_str = utf8_slice::from(&line, py_indent_prior);
-                if *within_inst {
-                    inst_str.push_str(&format!("{useful_str}\n"));
+                let useful_str = utf8_slice::from(line, py_indent_prior);
+                if inst.within {
+                    inst.body.push_str(&format!("{useful_str}\n"));
+                } else if useful_str.is_empty() {
+                    writeln!(stream).map_err(io_err)?;
                 } else {
                     // normal Python line
-                    writeln!(stream, "{useful_str}")?;
+                    writeln!(stream, "{}{useful_str}", " ".repeat(base_indent)).map_err(io_err)?;
                 }
             }
         }
@@ -97,17 +136,27 @@ impl Convert {
         .to_string()
     }
 
-    fn print_inst<W: Write>(
-        &self,
-        stream: &mut W,
-        inst_str: &str,
-        inst_indent_space: usize,
-    ) -> Result<(), Box<dyn Error>> {
+    fn print_inst<W: Write>(&self, stream: &mut W, inst: &InstBlock) -> Result<()> {
+        let inst_indent_space = inst.indent_space;
+        let yaml_err = |source: serde_yaml::Error| {
+            let mut span = inst.open_span.clone();
+            if let Some(location) = source.location() {
+                span.line += location.line();
+                span.column = inst.body_column + location.column() - 1;
+            }
+            Error::Yaml { span, source }
+        };
+        let inst_err = |message: &str| Error::Inst {
+            span: inst.open_span.clone(),
+            message: message.to_string(),
+        };
+        let io_err = |err| Error::io(&inst.open_span.file, err);
         let inst_map: serde_yaml::Value =
             serde_yaml::from_str(&self.apply_protected_verilog_regex(
-                Self::apply_protected_inst_group_regex(inst_str).as_str(),
-            ))?;
-        let mut inst_str_parsed = serde_yaml::to_string(&vec![&inst_map])?;
+                Self::apply_protected_inst_group_regex(&inst.body).as_str(),
+            ))
+            .map_err(yaml_err)?;
+        let mut inst_str_parsed = serde_yaml::to_string(&vec![&inst_map]).map_err(yaml_err)?;
         inst_str_parsed = Self::inst_group_print_to_dot_inst(
             self.undo_protected_brackets(inst_str_parsed.as_str())
                 .as_str(),
@@ -119,7 +168,8 @@ impl Convert {
             "{}_inst_file.write(f'''{}''')",
             " ".repeat(inst_indent_space),
             inst_str_parsed
-        )?;
+        )
+        .map_err(io_err)?;
         // print to .v
         match inst_map["module"].as_str() {
             Some(module) => writeln!(
@@ -127,8 +177,9 @@ impl Convert {
                 "{}print(f'{}', end='')",
                 " ".repeat(inst_indent_space),
                 self.undo_protected_brackets(module)
-            )?,
-            None => return Err("No module name found in the <INST>.".into()),
+            )
+            .map_err(io_err)?,
+            None => return Err(inst_err("No module name found in the <INST>.")),
         }
         let mut first_vparam = true;
         if let Some(vparams) = inst_map["vparams"].as_mapping() {
@@ -147,7 +198,8 @@ impl Convert {
                             } else {
                                 "False"
                             },
-                        )?;
+                        )
+                        .map_err(io_err)?;
                     } else {
                         writeln!(
                             stream,
@@ -165,15 +217,16 @@ impl Convert {
                             self.escape_single_quote(
                                 self.undo_protected_brackets(value_str).as_str()
                             )
-                        )?;
+                        )
+                        .map_err(io_err)?;
                     }
                 } else {
-                    return Err("Invalid vparams found in the <INST>.".into());
+                    return Err(inst_err("Invalid vparams found in the <INST>."));
                 }
             }
         }
         if !first_vparam {
-            writeln!(stream, "{}print(')')", " ".repeat(inst_indent_space))?;
+            writeln!(stream, "{}print(')')", " ".repeat(inst_indent_space)).map_err(io_err)?;
         }
         match inst_map["name"].as_str() {
             Some(name) => writeln!(
@@ -181,8 +234,9 @@ impl Convert {
                 "{}print(f' {} (')",
                 " ".repeat(inst_indent_space),
                 self.undo_protected_brackets(name)
-            )?,
-            None => return Err("No instantiation name found in the <INST>.".into()),
+            )
+            .map_err(io_err)?,
+            None => return Err(inst_err("No instantiation name found in the <INST>.")),
         }
         let mut first_port = true;
         if let Some(ports) = inst_map["ports"].as_mapping() {
@@ -201,7 +255,8 @@ impl Convert {
                             } else {
                                 "False"
                             },
-                        )?;
+                        )
+                        .map_err(io_err)?;
                     } else {
                         writeln!(
                             stream,
@@ -219,12 +274,13 @@ impl Convert {
                             self.escape_single_quote(
                                 self.undo_protected_brackets(value_str).as_str()
                             )
-                        )?;
+                        )
+                        .map_err(io_err)?;
                     }
                 }
             }
         }
-        writeln!(stream, "{}print(f'\\n);')", " ".repeat(inst_indent_space))?;
+        writeln!(stream, "{}print(f'\\n);')", " ".repeat(inst_indent_space)).map_err(io_err)?;
 
         Ok(())
     }
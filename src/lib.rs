@@ -14,6 +14,10 @@
 //! /*!
 //! b = a ** 2;               #  Python block
 //! */
+//! //! <INCLUDE common.pytv> #  splice another template
+//! //! for i in range(2):    #  inline control flow
+//! wire w_`i`;
+//! //! endfor                #  optional explicit end (`end`, `endfor`, `endif`, ...)
 //! ```
 //! The magic comment string can be configured (`!` as default).
 //!
@@ -32,13 +36,54 @@
 //!   [[paper at IEEE](https://ieeexplore.ieee.org/document/10396119)]
 //!   [[paper PDF](https://wqzhao.org/assets/zhao2023automatic.pdf)]
 
+mod batch;
+mod builder;
+mod buildgen;
+mod cache;
 mod config;
 mod convert;
+mod depfile;
+#[cfg(feature = "embed-python")]
+mod embed;
+mod error;
+mod include;
+mod limits;
+#[cfg(feature = "project")]
+mod project;
+mod pytoken;
+mod scope;
+mod source_map;
+mod substitution;
+mod watch;
+mod worker;
 
 #[cfg(feature = "inst")]
 mod inst;
 
+#[cfg(test)]
+mod test_util;
+
+pub use batch::Batch;
+pub use batch::BatchSummary;
+pub use batch::Command;
+pub use builder::ConfigBuilder;
+pub use builder::ConvertBuilder;
+pub use buildgen::BuildFile;
+pub use buildgen::BuildFormat;
 pub use config::Config;
 pub use config::FileOptions;
+pub use config::OnFailure;
+pub use config::PythonBackend;
+pub use config::RunMode;
 pub use convert::Convert;
 pub use convert::OutputPaths;
+pub use error::Error;
+pub use error::Result;
+pub use error::Span;
+pub use limits::Limit;
+pub use limits::ResourceLimits;
+#[cfg(feature = "project")]
+pub use project::ProjectConfig;
+pub use source_map::SourceLocation;
+pub use source_map::SourceMap;
+pub use watch::Watcher;
@@ -0,0 +1,373 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a limited Python script is checked for its deadline and output size.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the output of a killed Python script is still collected.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Limits of the Python script run after conversion.
+///
+/// The CPU time and address space limits are only applied on Linux.
+///
+/// # Example
+/// ```
+/// use pytv::{Convert, ResourceLimits, RunMode};
+/// use std::time::Duration;
+/// let convert = Convert::builder()
+///     .input("top.pytv")
+///     .run_mode(RunMode::Run)
+///     .limits(ResourceLimits {
+///         timeout: Some(Duration::from_secs(60)),
+///         output_size: Some(64 << 20),
+///         ..Default::default()
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The wall-clock time the script may run.
+    pub timeout: Option<Duration>,
+    /// The CPU time of the script, in seconds (Linux only).
+    pub cpu_time: Option<u64>,
+    /// The address space of the script, in bytes (Linux only).
+    pub memory: Option<u64>,
+    /// The size of the output (the Verilog written to stdout), in bytes.
+    pub output_size: Option<u64>,
+}
+
+/// A limit exceeded by a Python script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The wall-clock time limit.
+    Timeout(Duration),
+    /// The CPU time limit, in seconds.
+    CpuTime(u64),
+    /// The address space limit, in bytes.
+    Memory(u64),
+    /// The output size limit, in bytes.
+    OutputSize(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Timeout(timeout) => write!(f, "time limit of {} s", timeout.as_secs_f64()),
+            Limit::CpuTime(seconds) => write!(f, "CPU time limit of {seconds} s"),
+            Limit::Memory(bytes) => write!(f, "memory limit of {bytes} bytes"),
+            Limit::OutputSize(bytes) => write!(f, "output size limit of {bytes} bytes"),
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Returns `true` if no limit is set.
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Runs `command` with `stdin` as its input, collecting its stdout and stderr,
+    /// and kills it when a limit is exceeded.
+    ///
+    /// Returns the output with the limit exceeded, if any.
+    pub(crate) fn run(
+        &self,
+        mut command: Command,
+        stdin: Option<Vec<u8>>,
+    ) -> std::io::Result<(Output, Option<Limit>)> {
+        #[cfg(target_os = "linux")]
+        self.set_rlimits(&mut command);
+        let mut child = command
+            .stdin(match stdin {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let (Some(mut pipe), Some(stdin)) = (child.stdin.take(), stdin) {
+            // the script may write output before reading all of its input
+            std::thread::spawn(move || pipe.write_all(&stdin));
+        }
+        let output_exceeded = Arc::new(AtomicBool::new(false));
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let readers = [
+            collect(
+                child.stdout.take(),
+                Arc::clone(&stdout),
+                self.output_size
+                    .map(|size| (size, Arc::clone(&output_exceeded))),
+            ),
+            collect(child.stderr.take(), Arc::clone(&stderr), None),
+        ];
+        let start = Instant::now();
+        let mut limit = None;
+        let (status, cpu_time) = loop {
+            if let Some(exited) = try_wait(&mut child)? {
+                break exited;
+            }
+            if output_exceeded.load(Ordering::Relaxed) {
+                limit = self.output_size.map(Limit::OutputSize);
+            } else if let Some(timeout) = self.timeout.filter(|t| start.elapsed() >= *t) {
+                limit = Some(Limit::Timeout(timeout));
+            }
+            if limit.is_some() {
+                break (kill(&mut child)?, None);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        // processes started by the script may keep the pipes open after it is killed
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        for reader in readers {
+            while !reader.is_finished() && (limit.is_none() || Instant::now() < deadline) {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+        let output = Output {
+            status,
+            stdout: std::mem::take(&mut *stdout.lock().unwrap()),
+            stderr: std::mem::take(&mut *stderr.lock().unwrap()),
+        };
+        if limit.is_none() && output_exceeded.load(Ordering::Relaxed) {
+            limit = self.output_size.map(Limit::OutputSize);
+        }
+        let limit = limit.or_else(|| self.killed_by_rlimit(&output, cpu_time));
+        Ok((output, limit))
+    }
+
+    /// Sets the CPU time and address space limits of the child process.
+    #[cfg(target_os = "linux")]
+    fn set_rlimits(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        // the CPU time limit sends SIGXCPU, and SIGKILL a second later
+        let rlimits = [
+            (
+                libc::RLIMIT_CPU,
+                self.cpu_time.map(|s| (s, s.saturating_add(1))),
+            ),
+            (libc::RLIMIT_AS, self.memory.map(|bytes| (bytes, bytes))),
+        ];
+        if rlimits.iter().all(|(_, limit)| limit.is_none()) {
+            return;
+        }
+        // SAFETY: the closure only calls `setrlimit`, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in rlimits {
+                    let Some((soft, hard)) = limit else { continue };
+                    let rlimit = libc::rlimit {
+                        rlim_cur: soft as libc::rlim_t,
+                        rlim_max: hard as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Returns the limit which made the script fail, if it is the CPU time or address space limit.
+    ///
+    /// The CPU time limit is only reported if the script used up its CPU time (`cpu_time`),
+    /// and the address space limit if the script ended with an uncaught `MemoryError`.
+    fn killed_by_rlimit(&self, output: &Output, cpu_time: Option<Duration>) -> Option<Limit> {
+        if output.status.success() || cfg!(not(target_os = "linux")) {
+            return None;
+        }
+        if let Some(seconds) = self.cpu_time {
+            if cpu_time.is_some_and(|t| t >= Duration::from_secs(seconds)) {
+                return Some(Limit::CpuTime(seconds));
+            }
+        }
+        if let Some(bytes) = self.memory {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let last_line = stderr.lines().rev().find(|line| !line.trim().is_empty());
+            if last_line.is_some_and(|line| line.starts_with("MemoryError")) {
+                return Some(Limit::Memory(bytes));
+            }
+        }
+        None
+    }
+}
+
+/// Reads `pipe` into `buffer` on a new thread, until its end or until `limit` is exceeded.
+fn collect<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    limit: Option<(u64, Arc<AtomicBool>)>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let Some(mut pipe) = pipe else { return };
+        let mut chunk = [0; 8192];
+        while let Ok(n @ 1..) = pipe.read(&mut chunk) {
+            let mut buffer = buffer.lock().unwrap();
+            if let Some((size, exceeded)) = &limit {
+                if (buffer.len() + n) as u64 > *size {
+                    exceeded.store(true, Ordering::Relaxed);
+                    return;
+                }
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+    })
+}
+
+/// Returns the exit status of the child process if it has exited, and the CPU time it used
+/// (only known on Linux).
+fn try_wait(child: &mut Child) -> std::io::Result<Option<(ExitStatus, Option<Duration>)>> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        let mut status = 0;
+        // SAFETY: `rusage` is plain data, for which all zero bytes are valid.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        // SAFETY: the pointers are valid for the call, and the child is not waited for elsewhere
+        // once it has been reaped here.
+        let pid = unsafe {
+            libc::wait4(
+                child.id() as libc::pid_t,
+                &mut status,
+                libc::WNOHANG,
+                &mut usage,
+            )
+        };
+        if pid < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if pid == 0 {
+            return Ok(None);
+        }
+        let time = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        let cpu_time = time(usage.ru_utime) + time(usage.ru_stime);
+        Ok(Some((ExitStatus::from_raw(status), Some(cpu_time))))
+    }
+    #[cfg(not(target_os = "linux"))]
+    Ok(child.try_wait()?.map(|status| (status, None)))
+}
+
+/// Kills the child process and waits for it.
+fn kill(child: &mut Child) -> std::io::Result<std::process::ExitStatus> {
+    let _ = child.kill();
+    child.wait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::{Convert, Error};
+
+    #[test]
+    fn test_python_limits() {
+        use crate::RunMode;
+        let dir = TempDir::new("limits");
+        let run = |name: &str, source: &str, limits: ResourceLimits| {
+            let input = dir.join(name).to_string_lossy().to_string();
+            std::fs::write(&input, source).unwrap();
+            let convert = Convert::builder()
+                .input(&input)
+                .run_mode(RunMode::Run)
+                .limits(limits)
+                .build()
+                .unwrap();
+            (input, convert.convert_to_file())
+        };
+        let timeout = ResourceLimits {
+            timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let (input, result) = run("loop.pytv", "//! while True: pass\n", timeout.clone());
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Limit {
+                limit: Limit::Timeout(_),
+                ..
+            }
+        ));
+        assert_eq!(err.span().file, input);
+        assert_eq!(err.exit_code(), 11);
+        let output_size = ResourceLimits {
+            output_size: Some(1000),
+            ..Default::default()
+        };
+        let (_, result) = run(
+            "big.pytv",
+            "//! for i in range(1000):\nwire w`i`;\n",
+            output_size,
+        );
+        assert!(matches!(
+            result.unwrap_err(),
+            Error::Limit {
+                limit: Limit::OutputSize(1000),
+                ..
+            }
+        ));
+        let (input, result) = run("ok.pytv", "wire w;\n", timeout);
+        result.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(input.replace(".pytv", ".v")).unwrap(),
+            "wire w;\n"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_python_rlimits() {
+        use crate::RunMode;
+        let dir = TempDir::new("rlimits");
+        let run = |name: &str, source: &str, limits: ResourceLimits| {
+            let input = dir.write(name, source);
+            let convert = Convert::builder()
+                .input(&input)
+                .run_mode(RunMode::Run)
+                .limits(limits)
+                .build()
+                .unwrap();
+            convert.convert_to_file().unwrap_err()
+        };
+        let cpu_time = ResourceLimits {
+            cpu_time: Some(1),
+            ..Default::default()
+        };
+        let err = run("loop.pytv", "//! while True: pass\n", cpu_time.clone());
+        assert!(matches!(
+            err,
+            Error::Limit {
+                limit: Limit::CpuTime(1),
+                ..
+            }
+        ));
+        // killed by a signal before using up its CPU time
+        let source = "//! import os, signal\n//! os.kill(os.getpid(), signal.SIGKILL)\n";
+        let err = run("kill.pytv", source, cpu_time);
+        assert!(!matches!(err, Error::Limit { .. }), "{err:?}");
+        let memory = ResourceLimits {
+            memory: Some(1 << 30),
+            ..Default::default()
+        };
+        let err = run("alloc.pytv", "//! x = b'x' * (2 << 30)\n", memory.clone());
+        assert!(matches!(
+            err,
+            Error::Limit {
+                limit: Limit::Memory(_),
+                ..
+            }
+        ));
+        // `MemoryError` in the output of a script failing with another error
+        let source =
+            "//! import sys\n//! print('MemoryError', file=sys.stderr)\n//! raise ValueError\n";
+        let err = run("value.pytv", source, memory);
+        assert!(!matches!(err, Error::Limit { .. }), "{err:?}");
+    }
+}
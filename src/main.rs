@@ -1,8 +1,18 @@
-use pytv::Convert;
+use pytv::Command;
 
 fn main() {
-    let convert = Convert::from_args();
-    convert
-        .convert_to_file()
-        .unwrap_or_else(|err| eprintln!("Error: {}", err));
+    match Command::from_args() {
+        Command::Convert(batch) => {
+            let summary = batch.run();
+            summary.print();
+            std::process::exit(summary.exit_code());
+        }
+        Command::Buildgen(build_file) => {
+            if let Err(err) = build_file.write() {
+                eprintln!("Error: {}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
+        Command::Watch(watcher) => watcher.run(),
+    }
 }
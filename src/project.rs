@@ -0,0 +1,188 @@
+use crate::config::{PythonBackend, RunMode};
+use crate::error::{Error, Result, Span};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// File names of project configuration files, in the order of precedence within a directory.
+pub const PROJECT_FILES: &[&str] = &["pytv.toml", ".pytv.yaml"];
+
+/// A variable value in a project configuration file.
+///
+/// Strings are Python expressions; other scalars are converted to Python literals.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum VarValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Expr(String),
+}
+
+impl VarValue {
+    fn to_python(&self) -> String {
+        match self {
+            VarValue::Bool(true) => "True".to_string(),
+            VarValue::Bool(false) => "False".to_string(),
+            VarValue::Integer(n) => n.to_string(),
+            VarValue::Float(x) => format!("{x:?}"),
+            VarValue::Expr(expr) => expr.clone(),
+        }
+    }
+}
+
+/// Defaults of a project, read from `pytv.toml` or `.pytv.yaml`.
+///
+/// Relative paths are relative to the directory of the configuration file.
+///
+/// # Example
+/// ```toml
+/// magic = "!"
+/// tab_size = 2
+/// preambles = ["common/preamble.py"]
+/// include_dirs = ["common"]
+/// output_dir = "build"
+/// python = "python3.11"
+/// python_path = ["scripts"]
+/// python_cwd = "data"
+/// python_stdin = true
+/// cache = true
+/// python_backend = "worker"
+/// run = "run-and-delete" # "convert", "run" or "run-and-delete"
+///
+/// [vars]
+/// WIDTH = 8
+/// NAME = "'top'"
+///
+/// [python_env]
+/// CHIP = "a100"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// The path of the configuration file.
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// The magic comment string.
+    pub magic: Option<String>,
+    /// The tab size.
+    pub tab_size: Option<u32>,
+    /// The preamble Python files.
+    pub preambles: Vec<String>,
+    /// The directories searched for included templates.
+    pub include_dirs: Vec<String>,
+    /// User-defined variables.
+    vars: BTreeMap<String, VarValue>,
+    /// The directory of output files.
+    pub output_dir: Option<String>,
+    /// The Python interpreter.
+    pub python: Option<String>,
+    /// The directories prepended to the `PYTHONPATH` of the Python script.
+    pub python_path: Vec<String>,
+    /// Extra environment variables of the Python script.
+    pub python_env: BTreeMap<String, String>,
+    /// The working directory of the Python script.
+    pub python_cwd: Option<String>,
+    /// Whether the Python script is piped to the interpreter over stdin.
+    pub python_stdin: Option<bool>,
+    /// Whether templates whose inputs are unchanged since the last conversion are skipped.
+    pub cache: Option<bool>,
+    /// How the Python script is run.
+    pub python_backend: Option<PythonBackend>,
+    /// Whether the Python script is run, and whether it is deleted afterwards.
+    pub run: Option<RunMode>,
+}
+
+impl ProjectConfig {
+    /// Finds the nearest project configuration file,
+    /// walking up from the directory of `input` (or the current directory for stdin).
+    pub fn discover(input: &Path) -> Option<PathBuf> {
+        let dir = input.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let dir = match dir {
+            Some(dir) => dir.canonicalize().ok()?,
+            None => std::env::current_dir().ok()?,
+        };
+        dir.ancestors()
+            .flat_map(|dir| PROJECT_FILES.iter().map(move |name| dir.join(name)))
+            .find(|path| path.is_file())
+    }
+
+    /// Reads a project configuration file (TOML, or YAML with a `.yaml`/`.yml` extension).
+    pub fn load(path: &Path) -> Result<ProjectConfig> {
+        let file = path.to_string_lossy().to_string();
+        let text = std::fs::read_to_string(path).map_err(|err| Error::io(&file, err))?;
+        let is_yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let config_err = |line: usize, message: String| Error::Config {
+            span: Span::new(&file, line, 0),
+            message: format!("Invalid project configuration: {message}"),
+        };
+        let mut config: ProjectConfig = if is_yaml {
+            serde_yaml::from_str(&text).map_err(|err| {
+                let line = err.location().map_or(0, |location| location.line());
+                config_err(line, err.to_string())
+            })?
+        } else {
+            toml::from_str(&text).map_err(|err| {
+                let line = err
+                    .span()
+                    .map_or(0, |span| text[..span.start].lines().count().max(1));
+                config_err(line, err.message().to_string())
+            })?
+        };
+        config.path = path.to_path_buf();
+        Ok(config)
+    }
+
+    /// Resolves a path in the configuration file relative to its directory.
+    pub fn resolve(&self, path: &str) -> String {
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        dir.join(path).to_string_lossy().replace("\\", "/")
+    }
+
+    /// Returns the user-defined variables as Python assignments (`name`, `expression`).
+    pub fn vars(&self) -> Vec<(String, String)> {
+        self.vars
+            .iter()
+            .map(|(name, value)| (name.clone(), value.to_python()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_project_config_discovery() {
+        let dir = TempDir::new("project");
+        std::fs::create_dir_all(dir.join("rtl/core")).unwrap();
+        dir.write(
+            "pytv.toml",
+            "tab_size = 2\npreambles = [\"pre.py\"]\nrun = \"run\"\n[vars]\nN = 8\nB = false\n",
+        );
+        let input = dir.join("rtl/core/top.pytv");
+        let path = ProjectConfig::discover(&input).unwrap();
+        assert!(path.ends_with("pytv.toml"));
+        let project = ProjectConfig::load(&path).unwrap();
+        assert_eq!(project.tab_size, Some(2));
+        assert_eq!(project.run, Some(RunMode::Run));
+        assert!(project.resolve(&project.preambles[0]).ends_with("/pre.py"));
+        assert_eq!(
+            project.vars(),
+            vec![
+                ("B".to_string(), "False".to_string()),
+                ("N".to_string(), "8".to_string())
+            ]
+        );
+
+        dir.write("rtl/.pytv.yaml", "magic: '@'\nunknown: 1\n");
+        let path = ProjectConfig::discover(&input).unwrap();
+        assert!(path.ends_with("rtl/.pytv.yaml"));
+        let err = ProjectConfig::load(&path).unwrap_err();
+        assert!(matches!(err, Error::Config { .. }));
+        assert_eq!(err.span().line, 2);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::error::Span;
+
+/// A nesting context of the Python tokenizer.
+#[derive(Debug, Clone, PartialEq)]
+enum Context {
+    /// Inside brackets, expecting the closing bracket.
+    Bracket(char),
+    /// Inside a string literal.
+    Str {
+        quote: char,
+        triple: bool,
+        fmt: bool,
+    },
+    /// Inside a replacement field (`{...}`) of an f-string.
+    Field,
+}
+
+/// Tracks a Python logical line, which may span multiple physical lines.
+///
+/// Bracket depth, string literals (including triple-quoted and f-strings),
+/// comments and backslash continuations are taken into account,
+/// so that only a `:` ending the logical line at the top level opens a block.
+#[derive(Debug, Default)]
+pub(crate) struct LogicalLine {
+    stack: Vec<Context>,
+    /// Whether the last physical line ends with a backslash.
+    continuation: bool,
+    /// The last significant character at the top level.
+    last: Option<char>,
+    /// The indentation of the first physical line.
+    pub(crate) indent: usize,
+    /// The location of the first physical line.
+    pub(crate) span: Span,
+    /// The first physical line.
+    pub(crate) first_line: String,
+}
+
+impl LogicalLine {
+    /// Returns `true` if the logical line continues on the next physical line.
+    pub(crate) fn is_open(&self) -> bool {
+        !self.stack.is_empty() || self.continuation
+    }
+
+    /// Returns `true` if the complete logical line opens a block (ends with `:`).
+    pub(crate) fn opens_block(&self) -> bool {
+        !self.is_open() && self.last == Some(':')
+    }
+
+    /// Feeds a physical line located at `span`.
+    pub(crate) fn feed(&mut self, line: &str, span: &Span) {
+        if !self.is_open() {
+            self.last = None;
+            self.indent = line.len() - line.trim_start().len();
+            self.span = span.clone();
+            self.first_line = line.to_string();
+        }
+        self.continuation = false;
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if let Some(Context::Str { quote, triple, fmt }) = self.stack.last().cloned() {
+                if c == '\\' || (fmt && (c == '{' || c == '}') && next == Some(c)) {
+                    // escape sequence or doubled brace
+                    i += 2;
+                } else if fmt && c == '{' {
+                    self.stack.push(Context::Field);
+                    i += 1;
+                } else if c == quote && (!triple || chars[i..].starts_with(&[quote; 3])) {
+                    self.stack.pop();
+                    self.mark(quote);
+                    i += if triple { 3 } else { 1 };
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+            match c {
+                '#' => break,
+                '\\' if i + 1 == chars.len() => self.continuation = true,
+                '(' | '[' | '{' => self.stack.push(Context::Bracket(match c {
+                    '(' => ')',
+                    '[' => ']',
+                    _ => '}',
+                })),
+                ')' | ']' | '}' => {
+                    self.stack.pop();
+                    self.mark(c);
+                }
+                '\'' | '"' => {
+                    let prefix = chars[..i]
+                        .iter()
+                        .rev()
+                        .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                        .collect::<String>()
+                        .to_ascii_lowercase();
+                    let triple = chars[i..].starts_with(&[c; 3]);
+                    self.stack.push(Context::Str {
+                        quote: c,
+                        triple,
+                        fmt: prefix.contains('f'),
+                    });
+                    i += if triple { 3 } else { 1 };
+                    continue;
+                }
+                c if c.is_whitespace() => {}
+                c => self.mark(c),
+            }
+            i += 1;
+        }
+        // single-quoted strings cannot span lines without a continuation
+        if !self.continuation {
+            while let Some(Context::Str { triple: false, .. }) = self.stack.last() {
+                self.stack.pop();
+            }
+        }
+    }
+
+    /// Records a significant character if it is at the top level.
+    fn mark(&mut self, c: char) {
+        if self.stack.is_empty() {
+            self.last = Some(c);
+        }
+    }
+}
+
+/// Returns the part of a Python line before its comment, if any.
+///
+/// `#` characters in string literals opened on the line do not start a comment.
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<(char, bool)> = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some(_) if c == '\\' => {
+                chars.next();
+            }
+            Some((q, triple))
+                if c == q && (!triple || line[i..].starts_with(&q.to_string().repeat(3))) =>
+            {
+                quote = None;
+                if triple {
+                    chars.nth(1);
+                }
+            }
+            Some(_) => {}
+            None if c == '#' => return &line[..i],
+            None if c == '\'' || c == '"' => {
+                let triple = line[i..].starts_with(&c.to_string().repeat(3));
+                if triple {
+                    chars.nth(1);
+                }
+                quote = Some((c, triple));
+            }
+            None => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the lines, returning whether the logical line is still open after each.
+    fn feed(logical: &mut LogicalLine, lines: &[&str]) -> Vec<bool> {
+        let span = Span::default();
+        lines
+            .iter()
+            .map(|line| {
+                logical.feed(line, &span);
+                logical.is_open()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("x = 1  # see a */"), "x = 1  ");
+        assert_eq!(strip_comment("s = 'a # b' */"), "s = 'a # b' */");
+        assert_eq!(
+            strip_comment("s = \"\"\"a\"#\"\"\" # c"),
+            "s = \"\"\"a\"#\"\"\" "
+        );
+        assert_eq!(strip_comment("s = 'it\\'s' # c"), "s = 'it\\'s' ");
+    }
+
+    #[test]
+    fn test_block_openers() {
+        for (line, opens) in [
+            ("for i in range(2):", true),
+            ("if a:  # comment", true),
+            ("x = d[1:]", false),
+            ("f = lambda: 0", false),
+            ("s = 'a:'", false),
+            ("t = f'{x:>4}'", false),
+            ("# for i in y:", false),
+        ] {
+            let mut logical = LogicalLine::default();
+            feed(&mut logical, &[line]);
+            assert_eq!(logical.opens_block(), opens, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_multi_line_logical_lines() {
+        let mut logical = LogicalLine::default();
+        assert_eq!(
+            feed(&mut logical, &["  if (a and", "      b):"]),
+            [true, false]
+        );
+        assert!(logical.opens_block());
+        assert_eq!(logical.indent, 2);
+        assert_eq!(logical.first_line, "  if (a and");
+        let mut logical = LogicalLine::default();
+        assert_eq!(
+            feed(&mut logical, &["s = '''a:", "{b}:", "'''"]),
+            [true, true, false]
+        );
+        assert!(!logical.opens_block());
+        let mut logical = LogicalLine::default();
+        assert_eq!(feed(&mut logical, &["x = 1 + \\", "  2"]), [true, false]);
+        // an unterminated single-quoted string ends with the line
+        let mut logical = LogicalLine::default();
+        assert_eq!(feed(&mut logical, &["s = 'a"]), [false]);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::error::{Error, Result, Span};
+
+/// Python clauses continuing the compound statement of the scope before them.
+const CONTINUATION_KEYWORDS: &[&str] = &["elif", "else", "except", "finally"];
+
+/// Python compound statements that can be ended explicitly with `end<keyword>`.
+const END_KEYWORDS: &[&str] = &["for", "if", "while", "with", "def", "class", "try", "match"];
+
+/// A Python scope opened by an inline line ending with `:`.
+#[derive(Debug)]
+struct Scope {
+    /// The keyword of the compound statement (`for`, `if`, ...).
+    keyword: String,
+    /// The indentation of the line opening the scope.
+    header_indent: usize,
+    /// The location of the line opening the scope.
+    span: Span,
+}
+
+/// Tracks the Python scopes of inline control flow.
+///
+/// Scopes are closed implicitly by a Python line at the same or lower indentation,
+/// or explicitly by `end` (any scope) or `end<keyword>` (e.g. `endfor`, `endif`).
+#[derive(Debug, Default)]
+pub(crate) struct ScopeStack {
+    scopes: Vec<Scope>,
+    /// Whether an explicit terminator has been used, requiring all scopes to be closed.
+    explicit: bool,
+}
+
+/// Returns the keyword of a Python statement, ignoring `async`.
+fn statement_keyword(statement: &str) -> &str {
+    let statement = statement.strip_prefix("async ").unwrap_or(statement);
+    let len = statement
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(statement.len());
+    &statement[..len]
+}
+
+impl ScopeStack {
+    /// Returns the keyword of an explicit terminator (`Some("")` for `end`), if `statement` is one.
+    pub(crate) fn end_keyword(statement: &str) -> Option<&str> {
+        let statement = statement.split('#').next().unwrap_or_default();
+        let keyword = statement.trim().strip_prefix("end")?;
+        (keyword.is_empty() || END_KEYWORDS.contains(&keyword)).then_some(keyword)
+    }
+
+    /// Records a Python line with the given indentation.
+    ///
+    /// Scopes whose header is not less indented are closed;
+    /// a new scope is opened if the line opens a block.
+    pub(crate) fn python_line(
+        &mut self,
+        statement: &str,
+        indent: usize,
+        opens_block: bool,
+        span: Span,
+    ) {
+        let mut keyword = statement_keyword(statement.trim());
+        let mut closed = None;
+        while self
+            .scopes
+            .last()
+            .is_some_and(|scope| scope.header_indent >= indent)
+        {
+            closed = self.scopes.pop();
+        }
+        if opens_block {
+            if let Some(sibling) = closed.as_ref().filter(|scope| {
+                scope.header_indent == indent && CONTINUATION_KEYWORDS.contains(&keyword)
+            }) {
+                keyword = &sibling.keyword;
+            }
+            self.scopes.push(Scope {
+                keyword: keyword.to_string(),
+                header_indent: indent,
+                span,
+            });
+        }
+    }
+
+    /// Closes the innermost scope with an explicit terminator.
+    ///
+    /// Returns the indentation of the line that opened the scope.
+    pub(crate) fn close(&mut self, keyword: &str, span: Span) -> Result<usize> {
+        self.explicit = true;
+        let terminator = format!("end{keyword}");
+        let Some(scope) = self.scopes.pop() else {
+            return Err(Error::Scope {
+                span,
+                message: format!("`{terminator}` has no open scope to end."),
+            });
+        };
+        if !keyword.is_empty() && keyword != scope.keyword {
+            return Err(Error::Scope {
+                span,
+                message: format!(
+                    "`{terminator}` does not match `{}` opened at line {}.",
+                    scope.keyword, scope.span.line
+                ),
+            });
+        }
+        Ok(scope.header_indent)
+    }
+
+    /// Returns the scopes left open, if explicit terminators are used,
+    /// with the location and a description of each.
+    pub(crate) fn unclosed(&self) -> Vec<(Span, String)> {
+        if !self.explicit {
+            return Vec::new();
+        }
+        self.scopes
+            .iter()
+            .map(|scope| {
+                (
+                    scope.span.clone(),
+                    format!(
+                        "`{}` scope opened at line {} is not ended.",
+                        scope.keyword, scope.span.line
+                    ),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize) -> Span {
+        Span::new("top.pytv", line, 1)
+    }
+
+    #[test]
+    fn test_end_keywords() {
+        assert_eq!(ScopeStack::end_keyword("end"), Some(""));
+        assert_eq!(ScopeStack::end_keyword(" endfor  # loop"), Some("for"));
+        assert_eq!(ScopeStack::end_keyword("endmodule"), None);
+        assert_eq!(ScopeStack::end_keyword("end_of_list = 1"), None);
+        assert_eq!(statement_keyword("async for x in y:"), "for");
+        assert_eq!(statement_keyword("if(a):"), "if");
+    }
+
+    #[test]
+    fn test_implicit_and_explicit_closing() {
+        let mut scopes = ScopeStack::default();
+        scopes.python_line("for i in range(2):", 0, true, span(1));
+        scopes.python_line("if i:", 4, true, span(2));
+        scopes.python_line("x = i", 8, false, span(3));
+        // `else` continues the `if` scope it closes
+        scopes.python_line("else:", 4, true, span(4));
+        assert!(scopes.unclosed().is_empty());
+        assert_eq!(scopes.close("if", span(5)).unwrap(), 4);
+        let err = scopes.close("while", span(6)).unwrap_err();
+        assert_eq!(err.span().line, 6);
+        assert!(err.to_string().contains("opened at line 1"));
+        scopes.python_line("while True:", 0, true, span(7));
+        let unclosed = scopes.unclosed();
+        assert_eq!(unclosed.len(), 1);
+        assert_eq!(unclosed[0].0.line, 7);
+        scopes.python_line("y = 1", 0, false, span(8));
+        assert!(scopes.close("", span(9)).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use regex::Regex;
+use std::io::{Result as IoResult, Write};
+
+/// Pseudo file name used for the PyTV utility prologue and epilogue.
+pub(crate) const UTILITY_FILE: &str = "<pytv utilities>";
+/// Pseudo file name used for PyTV macros.
+#[cfg(feature = "macro")]
+pub(crate) const MACRO_FILE: &str = "<pytv macros>";
+/// Pseudo file name used for user-defined variables.
+pub(crate) const VARS_FILE: &str = "<pytv vars>";
+
+/// Location in an input file that produced a line of the generated Python script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The input file (template, preamble or a `<pytv ...>` pseudo file).
+    pub file: String,
+    /// The line number in the input file (1-based).
+    pub line: usize,
+    /// The original text of the input line.
+    pub text: String,
+}
+
+/// Maps lines of the generated Python script back to their input locations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    lines: Vec<Option<SourceLocation>>,
+    inputs: Vec<String>,
+}
+
+impl SourceMap {
+    /// Returns the input location of a generated script line (1-based), if known.
+    pub fn lookup(&self, line: usize) -> Option<&SourceLocation> {
+        line.checked_sub(1)
+            .and_then(|idx| self.lines.get(idx))
+            .and_then(Option::as_ref)
+    }
+
+    /// Returns the input files read (template, included templates and preambles), in order.
+    pub fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    /// Returns the number of generated script lines recorded.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns `true` if no generated script line is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Returns the input location of the innermost traceback frame in the generated script.
+    ///
+    /// Frames in input files are preferred to those in `<pytv ...>` pseudo files.
+    pub fn traceback_location(&self, script: &str, traceback: &str) -> Option<&SourceLocation> {
+        let frame_re = Regex::new(r#"^\s*File "(.*)", line (\d+)"#).unwrap();
+        let mut locations = traceback
+            .lines()
+            .rev()
+            .filter_map(|line| frame_re.captures(line))
+            .filter(|caps| same_file(&caps[1], script))
+            .filter_map(|caps| self.lookup(caps[2].parse().ok()?))
+            .peekable();
+        let innermost = locations.peek().copied();
+        locations
+            .find(|loc| !loc.file.starts_with('<'))
+            .or(innermost)
+    }
+
+    /// Rewrites a Python traceback so that frames in the generated script point to input lines.
+    ///
+    /// `script` is the path of the generated script as passed to the Python interpreter.
+    /// Frames of other files are kept as they are.
+    pub fn rewrite_traceback(&self, script: &str, traceback: &str) -> String {
+        let frame_re = Regex::new(r#"^(\s*)File "(.*)", line (\d+)(.*)$"#).unwrap();
+        let mut rewritten = String::with_capacity(traceback.len());
+        let mut lines = traceback.lines().peekable();
+        while let Some(line) = lines.next() {
+            let location = frame_re.captures(line).and_then(|caps| {
+                if !same_file(&caps[2], script) {
+                    return None;
+                }
+                let loc = self.lookup(caps[3].parse().ok()?)?;
+                Some((caps[1].to_string(), caps[4].to_string(), loc))
+            });
+            let Some((indent, rest, loc)) = location else {
+                rewritten.push_str(line);
+                rewritten.push('\n');
+                continue;
+            };
+            rewritten.push_str(&format!(
+                "{indent}File \"{}\", line {}{rest}\n",
+                loc.file, loc.line
+            ));
+            // replace the generated code line and drop its caret markers
+            if lines
+                .peek()
+                .is_some_and(|next| next.starts_with(&format!("{indent} ")))
+            {
+                lines.next();
+                rewritten.push_str(&format!("{indent}  {}\n", loc.text.trim()));
+                while lines.peek().is_some_and(|next| {
+                    let next = next.trim();
+                    !next.is_empty() && next.chars().all(|c| matches!(c, '^' | '~' | ' '))
+                }) {
+                    lines.next();
+                }
+            }
+        }
+        rewritten
+    }
+}
+
+/// Returns `true` if both paths refer to the same file (Python may report absolute paths).
+fn same_file(a: &str, b: &str) -> bool {
+    a == b
+        || match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+}
+
+/// Writer that records the input location of every line written through it.
+pub(crate) struct MappedWriter<W: Write> {
+    inner: W,
+    origin: Option<SourceLocation>,
+    map: SourceMap,
+}
+
+impl<W: Write> MappedWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            origin: None,
+            map: SourceMap::default(),
+        }
+    }
+
+    /// Sets the input location attributed to lines written from now on.
+    pub(crate) fn set_origin(&mut self, file: &str, line: usize, text: &str) {
+        self.origin = Some(SourceLocation {
+            file: file.to_string(),
+            line,
+            text: text.to_string(),
+        });
+    }
+
+    /// Records an input file read, once.
+    pub(crate) fn add_input(&mut self, file: &str) {
+        if !self.map.inputs.iter().any(|input| input == file) {
+            self.map.inputs.push(file.to_string());
+        }
+    }
+
+    /// Writes a multi-line section, attributing each line to itself in the pseudo file `file`.
+    pub(crate) fn write_section(&mut self, file: &str, section: &str) -> IoResult<()> {
+        for (idx, line) in section.lines().enumerate() {
+            self.set_origin(file, idx + 1, line);
+            writeln!(self, "{line}")?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn into_source_map(self) -> SourceMap {
+        self.map
+    }
+}
+
+impl<W: Write> Write for MappedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.inner.write(buf)?;
+        for _ in buf[..written].iter().filter(|&&b| b == b'\n') {
+            self.map.lines.push(self.origin.clone());
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
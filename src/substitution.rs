@@ -0,0 +1,236 @@
+/// Verilog/SystemVerilog compiler directives, which are never PyTV expressions.
+const VERILOG_DIRECTIVES: &[&str] = &[
+    "__FILE__",
+    "__LINE__",
+    "begin_keywords",
+    "celldefine",
+    "default_decay_time",
+    "default_nettype",
+    "default_trireg_strength",
+    "define",
+    "delay_mode_distributed",
+    "delay_mode_path",
+    "delay_mode_unit",
+    "delay_mode_zero",
+    "else",
+    "elsif",
+    "end_keywords",
+    "endcelldefine",
+    "endif",
+    "ifdef",
+    "ifndef",
+    "include",
+    "line",
+    "nounconnected_drive",
+    "pragma",
+    "resetall",
+    "timescale",
+    "unconnected_drive",
+    "undef",
+    "undefineall",
+];
+
+/// A piece of a Verilog line.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Token<'a> {
+    /// Verilog text copied as it is.
+    Text(String),
+    /// PyTV expression to be substituted.
+    Expr(&'a str),
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits a Verilog line into text and PyTV expressions between `open` and `close`.
+///
+/// A delimiter preceded by a backslash (e.g. `` \` ``) is kept as literal text.
+/// With backtick delimiters, see [`tokenize_backtick`].
+pub(crate) fn tokenize<'a>(line: &'a str, open: &str, close: &str) -> Vec<Token<'a>> {
+    if open == "`" && close == "`" {
+        return tokenize_backtick(line);
+    }
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        let rest = &line[i..];
+        if c == '\\' && rest[1..].starts_with(open) {
+            text.push_str(open);
+            i += 1 + open.len();
+        } else if let Some(close_pos) = rest
+            .strip_prefix(open)
+            .and_then(|expr| expr.find(close))
+            .filter(|&close_pos| close_pos > 0)
+        {
+            if !text.is_empty() {
+                tokens.push(Token::Text(std::mem::take(&mut text)));
+            }
+            let expr_start = i + open.len();
+            tokens.push(Token::Expr(&line[expr_start..expr_start + close_pos]));
+            i = expr_start + close_pos + close.len();
+        } else {
+            text.push(c);
+            i += c.len_utf8();
+        }
+    }
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+/// Splits a Verilog line into text and PyTV expressions delimited by backticks.
+///
+/// Backticks that belong to Verilog are kept as text:
+/// - compiler directives (`` `define ``, `` `ifdef ``, `` `timescale ``, ...),
+///   followed by whitespace, `(` or the end of the line;
+/// - macro usages (`` `NAME `` followed by an operator or a space and another macro, like `` `A + `B ``);
+/// - macro text operators (`` `" ``, ``` `` ```, `` `\`" ``) in a `` `define `` line;
+/// - escaped backticks (`` \` ``).
+fn tokenize_backtick(line: &str) -> Vec<Token<'_>> {
+    let in_define = line.trim_start().starts_with("`define");
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        if c == '\\' && line[i + 1..].starts_with('`') {
+            text.push('`');
+            i += 2;
+            continue;
+        }
+        if c != '`' {
+            text.push(c);
+            i += c.len_utf8();
+            continue;
+        }
+        let rest = &line[i + 1..];
+        let ident_len = rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len());
+        let ident = &rest[..ident_len];
+        let after_ident = rest[ident_len..].chars().next();
+        // a directive name followed by `.`, `[`, ... is a PyTV expression like `` `line.upper()` ``
+        let ends_directive = after_ident.is_none_or(|c| c.is_whitespace() || c == '(');
+        if VERILOG_DIRECTIVES.contains(&ident) && ends_directive {
+            text.push('`');
+            text.push_str(ident);
+            i += 1 + ident_len;
+            continue;
+        }
+        if in_define {
+            if let Some(op) = ["\\`\"", "\"", "`"].iter().find(|op| rest.starts_with(*op)) {
+                text.push('`');
+                text.push_str(op);
+                i += 1 + op.len();
+                continue;
+            }
+        }
+        let close = rest.find('`');
+        // a macro usage is followed by text which cannot end an expression
+        // before the next backtick, like the ` + ` in `` `A + `B ``
+        let is_macro = |close: usize| {
+            ident_len > 0
+                && ident_len < close
+                && rest[..close]
+                    .ends_with(|c: char| c.is_whitespace() || "+-*/%&|^~!<>=?:,(".contains(c))
+                && rest[close + 1..].starts_with(|c: char| is_ident_char(c))
+        };
+        match close {
+            Some(0) => {
+                text.push_str("``");
+                i += 2;
+            }
+            Some(close) if !is_macro(close) => {
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+                tokens.push(Token::Expr(&rest[..close]));
+                i += close + 2;
+            }
+            _ => {
+                // macro usage or unmatched backtick
+                text.push('`');
+                i += 1;
+            }
+        }
+    }
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+/// Replaces the PyTV expressions in a line with `{prefix}{{expr}}{suffix}`,
+/// and applies `escape` to the remaining text.
+pub(crate) fn substitute(
+    line: &str,
+    open: &str,
+    close: &str,
+    prefix: &str,
+    suffix: &str,
+    escape: impl Fn(&str) -> String,
+) -> String {
+    tokenize(line, open, close)
+        .into_iter()
+        .map(|token| match token {
+            Token::Text(text) => escape(&text),
+            Token::Expr(expr) => format!("{prefix}{{{expr}}}{suffix}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exprs(line: &str) -> Vec<&str> {
+        tokenize(line, "`", "`")
+            .into_iter()
+            .filter_map(|token| match token {
+                Token::Expr(expr) => Some(expr),
+                Token::Text(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backtick_expressions_and_macros() {
+        assert_eq!(exprs("wire w_`i+1`_d;"), ["i+1"]);
+        assert_eq!(exprs("wire `name`_q, `name`_d;"), ["name", "name"]);
+        assert_eq!(exprs("wire [`n - 1`:0] a;"), ["n - 1"]);
+        assert_eq!(exprs("assign a = `A + `B;"), Vec::<&str>::new());
+        assert_eq!(exprs("assign a = `A+`n`;"), ["n"]);
+        assert_eq!(exprs("`ifdef `name` `endif"), ["name"]);
+        // PyTV variables named like directives
+        assert_eq!(exprs("wire `line.upper()`;"), ["line.upper()"]);
+        assert_eq!(exprs("wire `line`;"), ["line"]);
+        assert_eq!(exprs("`include `include_dirs[0]`"), ["include_dirs[0]"]);
+        assert_eq!(
+            exprs("`define W `define_map[\"x\"]`"),
+            ["define_map[\"x\"]"]
+        );
+        assert_eq!(exprs("`timescale 1ns/1ps"), Vec::<&str>::new());
+        assert_eq!(
+            tokenize("a `` b \\`c", "`", "`"),
+            [Token::Text("a `` b `c".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_custom_delimiter_tokens() {
+        assert_eq!(
+            tokenize("w${i}_${ {j} }", "${", "}"),
+            [
+                Token::Text("w".to_string()),
+                Token::Expr("i"),
+                Token::Text("_".to_string()),
+                Token::Expr(" {j"),
+                Token::Text(" }".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize("\\${x} ${}", "${", "}"),
+            [Token::Text("${x} ${}".to_string())]
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use crate::batch::{Batch, BatchSummary};
+use crate::config::{BatchArgs, Settings};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// How often the watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The modification time and size of a file, or `None` if it does not exist.
+type FileState = Option<(SystemTime, u64)>;
+
+/// Converts templates whenever the files they depend on change (`pytv --watch`).
+///
+/// The inputs are expanded again and the project configuration is read again on every change,
+/// so that new templates are picked up.
+/// The cache is turned on, so templates whose inputs are unchanged are skipped,
+/// so only the affected templates are converted (and their Python scripts run).
+/// Errors are printed, and watching goes on, also if the inputs cannot be expanded.
+#[derive(Debug)]
+pub struct Watcher {
+    settings: Box<Settings>,
+    batch_args: BatchArgs,
+}
+
+impl Watcher {
+    /// Creates a `Watcher` from the parsed command line arguments.
+    pub(crate) fn new(settings: Settings, batch_args: BatchArgs) -> Watcher {
+        Watcher {
+            settings: Box::new(settings),
+            batch_args,
+        }
+    }
+
+    /// Converts the templates, then polls the files they depend on
+    /// and converts again whenever one of them changes. Never returns.
+    pub fn run(self) -> ! {
+        let mut dependencies = Vec::new();
+        loop {
+            let batch = Batch::from_settings(*self.settings.clone(), self.batch_args.clone());
+            let summary = match batch {
+                Ok(batch) => batch.run(),
+                Err(err) => BatchSummary {
+                    failed: vec![(String::new(), err)],
+                    ..Default::default()
+                },
+            };
+            Self::report(&summary);
+            // the files of earlier conversions stay watched, as a failed conversion
+            // does not know all of them (e.g. an include which was deleted)
+            dependencies.extend(summary.dependencies);
+            dependencies.sort();
+            dependencies.dedup();
+            let files = Self::watched_files(&self.batch_args.inputs, &dependencies);
+            eprintln!(
+                "Watching {} files for changes (Ctrl-C to stop)...",
+                files.len()
+            );
+            let before = Self::file_states(&files);
+            let changed = loop {
+                std::thread::sleep(POLL_INTERVAL);
+                let files = Self::watched_files(&self.batch_args.inputs, &dependencies);
+                let changed = Self::changed_files(&before, &Self::file_states(&files));
+                if !changed.is_empty() {
+                    break changed;
+                }
+            };
+            eprintln!("Changed: {}", changed.join(", "));
+            // let editors finish writing before converting
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Returns the templates found in the inputs now and the dependencies of the conversions.
+    fn watched_files(inputs: &[String], dependencies: &[String]) -> Vec<String> {
+        let templates = Batch::default().expand_inputs(inputs);
+        let templates = templates
+            .into_iter()
+            .map(|(template, _)| template.to_string_lossy().replace('\\', "/"));
+        let mut files: Vec<String> = templates.chain(dependencies.iter().cloned()).collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// Returns the state of each file.
+    fn file_states(files: &[String]) -> BTreeMap<String, FileState> {
+        files
+            .iter()
+            .map(|file| {
+                let state = std::fs::metadata(file)
+                    .and_then(|m| Ok((m.modified()?, m.len())))
+                    .ok();
+                (file.clone(), state)
+            })
+            .collect()
+    }
+
+    /// Returns the files added, removed or modified between two polls.
+    fn changed_files(
+        before: &BTreeMap<String, FileState>,
+        after: &BTreeMap<String, FileState>,
+    ) -> Vec<String> {
+        let mut changed: Vec<String> = after
+            .iter()
+            .filter(|(file, state)| before.get(*file) != Some(state))
+            .map(|(file, _)| file.clone())
+            .collect();
+        changed.extend(
+            before
+                .keys()
+                .filter(|file| !after.contains_key(*file))
+                .cloned(),
+        );
+        changed
+    }
+
+    /// Prints the errors and a one-line summary of a conversion.
+    fn report(summary: &BatchSummary) {
+        for (_, err) in &summary.failed {
+            eprintln!("Error: {}", err);
+        }
+        for (input, output) in &summary.generated {
+            eprintln!("Generated: {} -> {}", input, output.display());
+        }
+        eprintln!(
+            "{} generated, {} skipped, {} failed.",
+            summary.generated.len(),
+            summary.skipped.len(),
+            summary.failed.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::Convert;
+
+    #[test]
+    fn test_changed_files() {
+        let dir = TempDir::new("watch_changed");
+        let inc = dir.write("inc.pytv", "wire a;\n");
+        let new = dir.join("new.pytv").to_string_lossy().to_string();
+        let top = dir.write("top.pytv", "//! <INCLUDE inc.pytv>\n");
+        let files = [inc.clone(), new.clone(), top.clone()];
+        let before = Watcher::file_states(&files);
+        assert!(Watcher::changed_files(&before, &Watcher::file_states(&files)).is_empty());
+        std::fs::write(&inc, "wire a, b;\n").unwrap();
+        let after = Watcher::file_states(&files);
+        assert_eq!(Watcher::changed_files(&before, &after), [inc.as_str()]);
+        std::fs::write(&new, "wire c;\n").unwrap();
+        std::fs::remove_file(&top).unwrap();
+        let after = Watcher::file_states(&files);
+        assert_eq!(
+            Watcher::changed_files(&before, &after),
+            [inc, new, top.clone()]
+        );
+        // a file which is no longer watched
+        let before = Watcher::file_states(&files);
+        let after = Watcher::file_states(&files[..2]);
+        assert_eq!(Watcher::changed_files(&before, &after), [top]);
+    }
+
+    #[test]
+    fn test_watched_files() {
+        let dir = TempDir::new("watch_files");
+        let top = dir.write("top.pytv", "//! <INCLUDE inc.pytv>\n");
+        let inc = dir.write("inc.pytv", "wire a;\n");
+        let new = dir.write("new.pytv", "wire b;\n");
+        let mut dependencies = Vec::new();
+        let mut convert = |file: &str| {
+            let mut batch = Batch::default();
+            batch.add(file, Convert::builder().input(file).build());
+            let summary = batch.run();
+            dependencies.extend(summary.dependencies);
+            (summary.failed.len(), dependencies.clone())
+        };
+        let inputs = std::slice::from_ref(&top);
+        let (_, deps) = convert(&top);
+        let files = Watcher::watched_files(inputs, &deps);
+        assert_eq!(files, [inc.clone(), top.clone()]);
+        // a new include
+        std::fs::write(&top, "//! <INCLUDE inc.pytv>\n//! <INCLUDE new.pytv>\n").unwrap();
+        let (_, deps) = convert(&top);
+        let files = Watcher::watched_files(inputs, &deps);
+        assert_eq!(files, [inc, new.clone(), top.clone()]);
+        // a deleted include stays watched, so that restoring it converts again
+        std::fs::remove_file(&new).unwrap();
+        let (failed, deps) = convert(&top);
+        assert_eq!(failed, 1);
+        assert_eq!(Watcher::watched_files(inputs, &deps), files);
+        // a new template in an input directory
+        let dirs = [dir.to_string_lossy().to_string()];
+        let other = dir.write("other.pytv", "wire c;\n");
+        let files = Watcher::watched_files(&dirs, &deps);
+        assert!(files.contains(&other) && files.contains(&new), "{files:?}");
+    }
+}
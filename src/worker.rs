@@ -0,0 +1,335 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+
+/// Python code defining `_run`, which runs a script as if it was run by its own interpreter
+/// and returns its exit code, its output (the Verilog), its `.inst` contents and its stderr.
+///
+/// Each script runs in a fresh `__main__` namespace with its stdout, stderr and `.inst` file captured.
+/// The working directory, `sys.path`, the environment and `open` are restored after it,
+/// and the functions it registers with `atexit` are run after it
+/// (the dependency tracking hook writes its file in one).
+/// The modules it imported from its working directory or its `sys.path` entries are unloaded,
+/// so that the next script sees their changes, while library modules stay loaded.
+pub(crate) const RUNNER_SCRIPT: &str = r#"
+import atexit, builtins, contextlib, importlib, io, os, sys, sysconfig, traceback
+_INST_FILE = '<pytv-inst>'
+
+class _Capture(io.StringIO):
+    def close(self):
+        pass
+
+def _unload_modules(loaded, dirs):
+    libraries = {sysconfig.get_path(name) for name in ('stdlib', 'platstdlib', 'purelib', 'platlib')}
+    libraries.update(p for p in sys.path if os.path.basename(p) in ('site-packages', 'dist-packages'))
+    libraries = tuple(os.path.join(os.path.abspath(p), '') for p in libraries if p)
+    dirs = tuple(os.path.join(os.path.abspath(d), '') for d in dirs)
+    for name in [name for name in sys.modules if name not in loaded]:
+        file = getattr(sys.modules[name], '__file__', None)
+        if isinstance(file, str):
+            file = os.path.abspath(file)
+            if file.startswith(dirs) and not file.startswith(libraries):
+                del sys.modules[name]
+
+def _run(script, file_name, cwd, paths, env):
+    stdout, stderr, inst = io.StringIO(), io.StringIO(), _Capture()
+    saved = os.getcwd(), list(sys.path), list(sys.argv), dict(os.environ)
+    saved_functions = builtins.open, io.open, atexit.register
+    loaded = set(sys.modules)
+    exit_functions = []
+    def _open(file, *args, **kwargs):
+        return inst if file == _INST_FILE else saved_functions[0](file, *args, **kwargs)
+    def _register(function, *args, **kwargs):
+        exit_functions.append((function, args, kwargs))
+        return function
+    exit_code = 0
+    try:
+        os.chdir(cwd)
+        importlib.invalidate_caches()
+        sys.path[:0] = paths
+        sys.argv[:] = [file_name]
+        os.environ.update(item.split('=', 1) for item in env)
+        os.environ['PYTV_INST_FILE'] = _INST_FILE
+        builtins.open = io.open = _open
+        atexit.register = _register
+        with contextlib.redirect_stdout(stdout), contextlib.redirect_stderr(stderr):
+            try:
+                exec(compile(script, file_name, 'exec'), {'__name__': '__main__', '__builtins__': builtins})
+            except SystemExit as e:
+                if isinstance(e.code, int) or e.code is None:
+                    exit_code = e.code or 0
+                else:
+                    print(e.code, file=sys.stderr)
+                    exit_code = 1
+            except BaseException as e:
+                traceback.print_exception(type(e), e, e.__traceback__.tb_next)
+                exit_code = 1
+            for function, args, kwargs in reversed(exit_functions):
+                try:
+                    function(*args, **kwargs)
+                except Exception:
+                    traceback.print_exc()
+    finally:
+        os.chdir(saved[0])
+        sys.path[:], sys.argv[:] = saved[1], saved[2]
+        os.environ.clear()
+        os.environ.update(saved[3])
+        builtins.open, io.open, atexit.register = saved_functions
+        _unload_modules(loaded, [cwd] + paths)
+    return exit_code, stdout.getvalue(), inst.getvalue(), stderr.getvalue()
+"#;
+
+/// The loop of a Python worker, run with `python -c` after [`RUNNER_SCRIPT`].
+///
+/// Messages in both directions are a line with the number of fields,
+/// followed by each field as a line with its length in bytes and the bytes.
+/// A request holds the script, its file name, its working directory,
+/// the directories prepended to `sys.path` and the environment variables (`NAME=VALUE`),
+/// the last two separated by NUL characters.
+/// The response holds the fields returned by `_run`.
+const WORKER_SCRIPT: &str = r#"
+_requests = os.fdopen(os.dup(0), 'rb')
+_responses = os.fdopen(os.dup(1), 'wb')
+# the scripts must not read or write the protocol pipes
+os.dup2(os.open(os.devnull, os.O_RDONLY), 0)
+os.dup2(2, 1)
+
+def _read_message():
+    count = _requests.readline()
+    if not count:
+        return None
+    return [_requests.read(int(_requests.readline())) for _ in range(int(count))]
+
+def _write_message(fields):
+    _responses.write(b'%d\n' % len(fields))
+    for field in fields:
+        _responses.write(b'%d\n' % len(field) + field)
+    _responses.flush()
+
+def _split(field):
+    return [item.decode() for item in field.split(b'\0') if item]
+
+while True:
+    request = _read_message()
+    if request is None:
+        break
+    script, file_name, cwd, paths, env = request
+    exit_code, *outputs = _run(script, file_name.decode(), cwd.decode(), _split(paths), _split(env))
+    _write_message([str(exit_code).encode()] + [output.encode() for output in outputs])
+"#;
+
+/// The idle workers, which are reused by later scripts with the same interpreter.
+static IDLE_WORKERS: Mutex<Vec<PythonWorker>> = Mutex::new(Vec::new());
+
+/// A script run by a Python worker.
+#[derive(Debug)]
+pub(crate) struct Job<'a> {
+    /// The Python script.
+    pub script: &'a [u8],
+    /// The file name of the script, as in tracebacks.
+    pub file_name: &'a str,
+    /// The working directory of the script.
+    pub cwd: String,
+    /// The directories prepended to `sys.path`.
+    pub python_path: Vec<String>,
+    /// The environment variables set for the script.
+    pub env: Vec<(String, String)>,
+}
+
+/// What a script run by a Python worker wrote.
+#[derive(Debug)]
+pub(crate) struct JobOutput {
+    /// The exit code of the script, or `None` if the worker was killed or exited with 0 while running it.
+    pub exit_code: Option<i32>,
+    /// The output of the script (the Verilog).
+    pub stdout: Vec<u8>,
+    /// The contents of the `.inst` file.
+    pub inst: Vec<u8>,
+    /// The error output of the script.
+    pub stderr: Vec<u8>,
+}
+
+/// A long-lived Python process running scripts sent over a pipe (see [`WORKER_SCRIPT`]).
+///
+/// The worker exits when its stdin is closed, i.e. at the latest when PyTV exits.
+#[derive(Debug)]
+struct PythonWorker {
+    python: String,
+    child: Child,
+    requests: ChildStdin,
+    responses: BufReader<ChildStdout>,
+}
+
+impl PythonWorker {
+    /// Starts a worker with the interpreter `python`.
+    fn spawn(python: &str) -> std::io::Result<PythonWorker> {
+        let mut child = Command::new(python)
+            .args(["-u", "-c", &format!("{RUNNER_SCRIPT}{WORKER_SCRIPT}")])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let requests = child.stdin.take().expect("stdin is piped");
+        let responses = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(PythonWorker {
+            python: python.to_string(),
+            child,
+            requests,
+            responses,
+        })
+    }
+
+    /// Runs a job, returning `Err` if the worker exited.
+    fn run(&mut self, job: &Job) -> std::io::Result<JobOutput> {
+        let python_path = job.python_path.join("\0");
+        let env = job
+            .env
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"));
+        let env = env.collect::<Vec<_>>().join("\0");
+        let fields: [&[u8]; 5] = [
+            job.script,
+            job.file_name.as_bytes(),
+            job.cwd.as_bytes(),
+            python_path.as_bytes(),
+            env.as_bytes(),
+        ];
+        let mut request = format!("{}\n", fields.len()).into_bytes();
+        for field in fields {
+            request.extend_from_slice(format!("{}\n", field.len()).as_bytes());
+            request.extend_from_slice(field);
+        }
+        self.requests.write_all(&request)?;
+        self.requests.flush()?;
+        let [exit_code, stdout, inst, stderr] = self.read_response()?;
+        Ok(JobOutput {
+            exit_code: String::from_utf8_lossy(&exit_code).parse().ok(),
+            stdout,
+            inst,
+            stderr,
+        })
+    }
+
+    /// Reads the response to a job.
+    fn read_response(&mut self) -> std::io::Result<[Vec<u8>; 4]> {
+        let mut fields: [Vec<u8>; 4] = Default::default();
+        if self.read_number()? != fields.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "invalid response of the Python worker",
+            ));
+        }
+        for field in &mut fields {
+            let len = self.read_number()?;
+            field.resize(len, 0);
+            self.responses.read_exact(field)?;
+        }
+        Ok(fields)
+    }
+
+    /// Reads a line holding a number.
+    fn read_number(&mut self) -> std::io::Result<usize> {
+        let mut line = String::new();
+        if self.responses.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        line.trim_end().parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid response of the Python worker: {line:?}"),
+            )
+        })
+    }
+}
+
+/// Runs a job in an idle worker with the interpreter `python`, starting one if there is none.
+///
+/// If the worker exits while running the script (e.g. the script calls `os._exit`),
+/// the output has the exit code of the worker (`None` if it is 0), and the worker is not reused.
+pub(crate) fn run(python: &str, job: &Job) -> std::io::Result<JobOutput> {
+    let idle = {
+        let mut workers = IDLE_WORKERS.lock().unwrap();
+        let position = workers.iter().position(|worker| worker.python == python);
+        position.map(|i| workers.swap_remove(i))
+    };
+    let mut worker = match idle {
+        Some(worker) => worker,
+        None => PythonWorker::spawn(python)?,
+    };
+    match worker.run(job) {
+        Ok(output) => {
+            IDLE_WORKERS.lock().unwrap().push(worker);
+            Ok(output)
+        }
+        Err(_) => {
+            let _ = worker.child.kill();
+            let status = worker.child.wait()?;
+            // the output of the script is lost, so even exiting with 0 is a failure
+            Ok(JobOutput {
+                exit_code: status.code().filter(|code| *code != 0),
+                stdout: Vec::new(),
+                inst: Vec::new(),
+                stderr: format!("The Python worker exited unexpectedly ({status}).\n").into_bytes(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use crate::{Convert, Error, PythonBackend, RunMode};
+
+    #[test]
+    fn test_python_worker_backend() {
+        let dir = TempDir::new("worker");
+        dir.write("data.txt", "d\n");
+        let template = |name: &str, source: &str| {
+            let input = dir.join(name).to_string_lossy().to_string();
+            std::fs::write(&input, source).unwrap();
+            Convert::builder()
+                .input(&input)
+                .run_mode(RunMode::RunAndDelete)
+                .python_backend(PythonBackend::Worker)
+                .depfile(format!("{input}.d"))
+                .build()
+                .unwrap()
+        };
+        let a = template(
+            "a.pytv",
+            "//! w = open('data.txt').read().strip()\n//! _inst_file.write('a_inst\\n')\nwire `w`;\n",
+        );
+        a.convert_to_file().unwrap();
+        let outputs = a.outputs();
+        assert_eq!(
+            std::fs::read_to_string(&outputs.verilog_file).unwrap(),
+            "wire d;\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&outputs.inst_file).unwrap(),
+            "a_inst\n"
+        );
+        assert!(!outputs.python_script_file.exists());
+        let depfile = std::fs::read_to_string(dir.join("a.pytv.d")).unwrap();
+        assert!(depfile.contains("data.txt"));
+        // each script runs in a fresh namespace
+        let b = template("b.pytv", "//! leaked = 'w' in globals()\nwire `leaked`;\n");
+        assert_eq!(b.render_verilog().unwrap(), "wire False;\n");
+        // modules imported from the working directory are loaded again
+        dir.write("helper.py", "W = 4\n");
+        let d = template("d.pytv", "//! import helper\nwire [`helper.W`:0] d;\n");
+        assert_eq!(d.render_verilog().unwrap(), "wire [4:0] d;\n");
+        dir.write("helper.py", "W = 16\n");
+        assert_eq!(d.render_verilog().unwrap(), "wire [16:0] d;\n");
+        let c = template("c.pytv", "wire c;\n//! import os\n//! os._exit(3)\n");
+        let err = c.convert_to_file().unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Python {
+                exit_code: Some(3),
+                ..
+            }
+        ));
+        assert!(!c.outputs().verilog_file.exists());
+        a.convert_to_file().unwrap();
+    }
+}
@@ -1,7 +1,7 @@
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
 use pyo3::types::PyModule;
-use pytv::{Config, Convert, FileOptions};
+use pytv::Convert;
 use std::path::{Path, PathBuf};
 
 #[pyclass(module = "tverilog._core", frozen)]
@@ -24,7 +24,9 @@ struct ConversionArtifacts {
     magic="!",
     tab_size=4,
     vars=None,
-    preamble=None
+    preamble=None,
+    open_delimiter="`",
+    close_delimiter="`"
 ))]
 fn convert_template(
     input_file: String,
@@ -33,21 +35,26 @@ fn convert_template(
     tab_size: u32,
     vars: Option<Vec<(String, String)>>,
     preamble: Option<String>,
+    open_delimiter: &str,
+    close_delimiter: &str,
 ) -> PyResult<ConversionArtifacts> {
-    let config = Config::new(
-        magic.to_string(),
-        Config::default_template_re(),
-        false,
-        false,
-        tab_size,
-    );
-
-    let file_options = FileOptions {
-        input: input_file.clone(),
-        output: output_file.clone(),
-    };
-
-    let convert = Convert::new(config, file_options, vars, preamble);
+    let mut builder = Convert::builder()
+        .input(input_file.clone())
+        .magic(magic)
+        .delimiters(open_delimiter, close_delimiter)
+        .tab_size(tab_size);
+    if let Some(output_file) = &output_file {
+        builder = builder.output(output_file);
+    }
+    for (name, value) in vars.unwrap_or_default() {
+        builder = builder.var(name, value);
+    }
+    if let Some(preamble) = preamble {
+        builder = builder.preamble(preamble);
+    }
+    let convert = builder
+        .build()
+        .map_err(|err| PyRuntimeError::new_err(format!("invalid configuration: {err}")))?;
     let script = convert
         .render_python_script()
         .map_err(|err| PyRuntimeError::new_err(format!("conversion failed: {err}")))?;
@@ -59,6 +59,7 @@ def generate(
     *,
     output_file: str | os.PathLike[str] | None = None,
     magic: str = "!",
+    delimiters: tuple[str, str] = ("`", "`"),
     tab_size: int = 4,
     vars: Mapping[str, str] | None = None,
     preamble: str | os.PathLike[str] | None = None,
@@ -70,6 +71,12 @@ def generate(
     """Generate Verilog from a PyTV template using the native conversion bridge."""
     if not isinstance(magic, str):
         raise ConfigurationError("'magic' must be a string.")
+    if (
+        not isinstance(delimiters, tuple)
+        or len(delimiters) != 2
+        or not all(isinstance(d, str) for d in delimiters)
+    ):
+        raise ConfigurationError("'delimiters' must be a (open, close) tuple of strings.")
     if not isinstance(tab_size, int) or tab_size <= 0:
         raise ConfigurationError("'tab_size' must be a positive integer.")
     if not isinstance(use_context, bool):
@@ -104,6 +111,8 @@ def generate(
             tab_size,
             normalized_vars,
             None if preamble_path is None else str(preamble_path),
+            delimiters[0],
+            delimiters[1],
         )
     except Exception as exc:
         raise PytvConversionError(